@@ -0,0 +1,108 @@
+-- The schema as it was before migrations were tracked, so a fresh database can run every later migration.
+-- Existing databases already have all of it and skip each statement.
+DO $$ BEGIN
+    CREATE TYPE stage AS ENUM ('onstate', 'park', 'notattempted', 'failed');
+EXCEPTION WHEN duplicate_object THEN NULL;
+END $$;
+
+DO $$ BEGIN
+    CREATE TYPE drivetrain AS ENUM ('swerve', 'tank', 'other');
+EXCEPTION WHEN duplicate_object THEN NULL;
+END $$;
+
+DO $$ BEGIN
+    CREATE TYPE polish AS ENUM ('one', 'two', 'three', 'four', 'five');
+EXCEPTION WHEN duplicate_object THEN NULL;
+END $$;
+
+CREATE TABLE IF NOT EXISTS "Users" (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    is_notify BOOLEAN NOT NULL DEFAULT FALSE,
+    is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+    endpoint TEXT,
+    p256dh TEXT,
+    auth TEXT,
+    access_token TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "Events" (
+    event_key TEXT PRIMARY KEY,
+    steam_url TEXT
+);
+
+CREATE TABLE IF NOT EXISTS "EventState" (
+    event_key TEXT NOT NULL,
+    next_match TEXT,
+    last_match TEXT
+);
+
+CREATE TABLE IF NOT EXISTS "Teams" (
+    team_key TEXT NOT NULL,
+    event_key TEXT NOT NULL,
+    nickname TEXT NOT NULL,
+    PRIMARY KEY (team_key, event_key)
+);
+
+CREATE TABLE IF NOT EXISTS "TBAMatches" (
+    match_key TEXT PRIMARY KEY,
+    event_key TEXT NOT NULL,
+    time TIME NOT NULL,
+    red_1 TEXT NOT NULL,
+    red_2 TEXT NOT NULL,
+    red_3 TEXT NOT NULL,
+    blue_1 TEXT NOT NULL,
+    blue_2 TEXT NOT NULL,
+    blue_3 TEXT NOT NULL,
+    video BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS "TeamMatches" (
+    id SERIAL PRIMARY KEY,
+    match_key TEXT NOT NULL,
+    team_key TEXT NOT NULL,
+    is_fielded BOOLEAN NOT NULL,
+    is_leave_start BOOLEAN NOT NULL,
+    auto_speaker_succeed SMALLINT NOT NULL,
+    auto_speaker_missed SMALLINT NOT NULL,
+    auto_amp_succeed SMALLINT NOT NULL,
+    auto_amp_missed SMALLINT NOT NULL,
+    auto_piece_succeed SMALLINT NOT NULL,
+    auto_piece_missed SMALLINT NOT NULL,
+    tele_speaker_succeed SMALLINT NOT NULL,
+    tele_speaker_missed SMALLINT NOT NULL,
+    tele_amp_succeed SMALLINT NOT NULL,
+    tele_amp_missed SMALLINT NOT NULL,
+    trap_succeed BOOLEAN NOT NULL,
+    trap_missed BOOLEAN NOT NULL,
+    stage_enum stage NOT NULL,
+    skill SMALLINT NOT NULL,
+    notes TEXT NOT NULL,
+    is_broke BOOLEAN NOT NULL,
+    is_died BOOLEAN NOT NULL,
+    scout_id TEXT NOT NULL REFERENCES "Users" (id)
+);
+
+CREATE TABLE IF NOT EXISTS "TeamEvents" (
+    id BIGSERIAL PRIMARY KEY,
+    team_key TEXT NOT NULL,
+    event_key TEXT NOT NULL,
+    width SMALLINT NOT NULL,
+    length SMALLINT NOT NULL,
+    is_short BOOLEAN NOT NULL,
+    is_camera BOOLEAN NOT NULL,
+    drivetrain_enum drivetrain NOT NULL,
+    is_ground_intake BOOLEAN NOT NULL,
+    is_chute_intake BOOLEAN NOT NULL,
+    polish polish NOT NULL,
+    scout_id TEXT NOT NULL REFERENCES "Users" (id)
+);
+
+CREATE TABLE IF NOT EXISTS images (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    event_key TEXT NOT NULL,
+    team_key TEXT NOT NULL,
+    url TEXT NOT NULL,
+    scout_id TEXT NOT NULL
+);
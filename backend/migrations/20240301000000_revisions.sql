@@ -0,0 +1,20 @@
+-- Submission timestamps and soft deletes, so records can be corrected without losing history
+ALTER TABLE "TeamMatches" ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE "TeamMatches" ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+ALTER TABLE "TeamEvents" ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE "TeamEvents" ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+-- Every edit or delete of a scouting record stores the row before and after the change
+CREATE TABLE IF NOT EXISTS "Revisions" (
+    id BIGSERIAL PRIMARY KEY,
+    table_name TEXT NOT NULL,
+    record_id BIGINT NOT NULL,
+    action TEXT NOT NULL,
+    editor_id TEXT NOT NULL REFERENCES "Users" (id),
+    edited_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    old_value JSONB,
+    new_value JSONB
+);
+
+CREATE INDEX IF NOT EXISTS revisions_record_idx ON "Revisions" (table_name, record_id);
@@ -0,0 +1,36 @@
+-- Pit data is keyed by (team, event), older duplicates are soft deleted so their history is kept
+UPDATE "TeamEvents" a SET deleted_at = now()
+FROM "TeamEvents" b
+WHERE a.team_key = b.team_key
+  AND a.event_key = b.event_key
+  AND a.id < b.id
+  AND a.deleted_at IS NULL
+  AND b.deleted_at IS NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS team_events_team_event_idx
+    ON "TeamEvents" (team_key, event_key) WHERE deleted_at IS NULL;
+
+-- Pit scouts can fill a record in over several visits
+ALTER TABLE "TeamEvents" ALTER COLUMN width DROP NOT NULL;
+ALTER TABLE "TeamEvents" ALTER COLUMN length DROP NOT NULL;
+ALTER TABLE "TeamEvents" ALTER COLUMN is_short DROP NOT NULL;
+ALTER TABLE "TeamEvents" ALTER COLUMN is_camera DROP NOT NULL;
+ALTER TABLE "TeamEvents" ALTER COLUMN drivetrain_enum DROP NOT NULL;
+ALTER TABLE "TeamEvents" ALTER COLUMN is_ground_intake DROP NOT NULL;
+ALTER TABLE "TeamEvents" ALTER COLUMN is_chute_intake DROP NOT NULL;
+ALTER TABLE "TeamEvents" ALTER COLUMN polish DROP NOT NULL;
+
+ALTER TABLE "TeamEvents" ADD COLUMN IF NOT EXISTS status TEXT GENERATED ALWAYS AS (
+    CASE
+        WHEN width IS NOT NULL
+            AND length IS NOT NULL
+            AND is_short IS NOT NULL
+            AND is_camera IS NOT NULL
+            AND drivetrain_enum IS NOT NULL
+            AND is_ground_intake IS NOT NULL
+            AND is_chute_intake IS NOT NULL
+            AND polish IS NOT NULL
+        THEN 'complete'
+        ELSE 'partial'
+    END
+) STORED;
@@ -0,0 +1,2 @@
+-- Photos attached to a pit scouting submission point at the record they were taken for
+ALTER TABLE images ADD COLUMN IF NOT EXISTS team_event_id BIGINT REFERENCES "TeamEvents" (id) ON DELETE SET NULL;
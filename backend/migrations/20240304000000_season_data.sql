@@ -0,0 +1,53 @@
+-- Match scouting fields live in a JSONB document validated against the season config in seasons/
+ALTER TABLE "TeamMatches" ADD COLUMN IF NOT EXISTS season INTEGER;
+ALTER TABLE "TeamMatches" ADD COLUMN IF NOT EXISTS data JSONB NOT NULL DEFAULT '{}'::jsonb
+    CHECK (jsonb_typeof(data) = 'object');
+
+-- Everything scouted before this was the 2024 game
+UPDATE "TeamMatches" SET season = 2024, data = jsonb_build_object(
+    'is_fielded', is_fielded,
+    'is_leave_start', is_leave_start,
+    'auto_speaker_succeed', auto_speaker_succeed,
+    'auto_speaker_missed', auto_speaker_missed,
+    'auto_amp_succeed', auto_amp_succeed,
+    'auto_amp_missed', auto_amp_missed,
+    'auto_piece_succeed', auto_piece_succeed,
+    'auto_piece_missed', auto_piece_missed,
+    'tele_speaker_succeed', tele_speaker_succeed,
+    'tele_speaker_missed', tele_speaker_missed,
+    'tele_amp_succeed', tele_amp_succeed,
+    'tele_amp_missed', tele_amp_missed,
+    'trap_succeed', trap_succeed,
+    'trap_missed', trap_missed,
+    'stage', CASE lower(stage_enum::text) WHEN 'onstate' THEN 'onstage' ELSE lower(stage_enum::text) END,
+    'skill', skill,
+    'notes', notes,
+    'is_broke', is_broke,
+    'is_died', is_died
+);
+
+ALTER TABLE "TeamMatches" ALTER COLUMN season SET NOT NULL;
+
+ALTER TABLE "TeamMatches"
+    DROP COLUMN is_fielded,
+    DROP COLUMN is_leave_start,
+    DROP COLUMN auto_speaker_succeed,
+    DROP COLUMN auto_speaker_missed,
+    DROP COLUMN auto_amp_succeed,
+    DROP COLUMN auto_amp_missed,
+    DROP COLUMN auto_piece_succeed,
+    DROP COLUMN auto_piece_missed,
+    DROP COLUMN tele_speaker_succeed,
+    DROP COLUMN tele_speaker_missed,
+    DROP COLUMN tele_amp_succeed,
+    DROP COLUMN tele_amp_missed,
+    DROP COLUMN trap_succeed,
+    DROP COLUMN trap_missed,
+    DROP COLUMN stage_enum,
+    DROP COLUMN skill,
+    DROP COLUMN notes,
+    DROP COLUMN is_broke,
+    DROP COLUMN is_died;
+
+CREATE INDEX IF NOT EXISTS team_matches_season_idx ON "TeamMatches" (season);
+CREATE INDEX IF NOT EXISTS team_matches_data_idx ON "TeamMatches" USING GIN (data jsonb_path_ops);
@@ -0,0 +1,8 @@
+-- Match scouting is scoped to an event like pit data and images already are
+ALTER TABLE "TeamMatches" ADD COLUMN IF NOT EXISTS event_key TEXT;
+UPDATE "TeamMatches" SET event_key = split_part(match_key, '_', 1) WHERE event_key IS NULL;
+ALTER TABLE "TeamMatches" ALTER COLUMN event_key SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS team_matches_event_idx ON "TeamMatches" (event_key, match_key);
+CREATE INDEX IF NOT EXISTS team_events_event_idx ON "TeamEvents" (event_key);
+CREATE INDEX IF NOT EXISTS images_event_team_idx ON images (event_key, team_key);
@@ -0,0 +1,6 @@
+-- Images are looked up by their UUID name, the key in the image store is kept alongside it
+ALTER TABLE images ADD COLUMN IF NOT EXISTS storage_key TEXT;
+UPDATE images SET storage_key = name || '.png' WHERE storage_key IS NULL;
+ALTER TABLE images ALTER COLUMN storage_key SET NOT NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS images_name_idx ON images (name);
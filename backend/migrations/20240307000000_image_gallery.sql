@@ -0,0 +1,7 @@
+-- Captions, tags and a primary photo per team for the pit scouting gallery
+ALTER TABLE images ADD COLUMN IF NOT EXISTS caption TEXT;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE images ADD COLUMN IF NOT EXISTS is_primary BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE UNIQUE INDEX IF NOT EXISTS images_primary_idx ON images (event_key, team_key) WHERE is_primary;
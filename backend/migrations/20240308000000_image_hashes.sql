@@ -0,0 +1,6 @@
+-- Content hashes to catch the same photo uploaded twice from a camera roll
+ALTER TABLE images ADD COLUMN IF NOT EXISTS content_hash TEXT;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS phash BIGINT;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS near_duplicate_of TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS images_content_hash_idx ON images (event_key, team_key, content_hash) WHERE content_hash IS NOT NULL;
@@ -0,0 +1,13 @@
+-- Scouting shifts, scouts get a push notification shortly before theirs starts
+CREATE TABLE IF NOT EXISTS "Shifts" (
+    id BIGSERIAL PRIMARY KEY,
+    event_key TEXT NOT NULL,
+    scout_id TEXT NOT NULL REFERENCES "Users" (id) ON DELETE CASCADE,
+    starts_at TIMESTAMPTZ NOT NULL,
+    ends_at TIMESTAMPTZ NOT NULL,
+    notified_at TIMESTAMPTZ,
+    CHECK (ends_at > starts_at)
+);
+
+CREATE INDEX IF NOT EXISTS shifts_event_idx ON "Shifts" (event_key, starts_at);
+CREATE INDEX IF NOT EXISTS shifts_pending_idx ON "Shifts" (starts_at) WHERE notified_at IS NULL;
@@ -0,0 +1,28 @@
+-- Scouts can get notifications on every device they subscribe from, not just the last one
+CREATE TABLE IF NOT EXISTS "PushSubscriptions" (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES "Users" (id) ON DELETE CASCADE,
+    endpoint TEXT NOT NULL UNIQUE,
+    p256dh TEXT NOT NULL,
+    auth TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS push_subscriptions_user_idx ON "PushSubscriptions" (user_id);
+
+INSERT INTO "PushSubscriptions" (user_id, endpoint, p256dh, auth)
+SELECT id, endpoint, p256dh, auth FROM "Users"
+WHERE endpoint IS NOT NULL AND p256dh IS NOT NULL AND auth IS NOT NULL
+ON CONFLICT (endpoint) DO NOTHING;
+
+-- is_notify is the overall switch, anyone who already subscribed has opted in
+UPDATE "Users" SET is_notify = TRUE WHERE endpoint IS NOT NULL;
+
+ALTER TABLE "Users" DROP COLUMN IF EXISTS endpoint;
+ALTER TABLE "Users" DROP COLUMN IF EXISTS p256dh;
+ALTER TABLE "Users" DROP COLUMN IF EXISTS auth;
+
+-- Which kinds of notification each scout wants
+ALTER TABLE "Users" ADD COLUMN IF NOT EXISTS notify_assignment BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE "Users" ADD COLUMN IF NOT EXISTS notify_shift BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE "Users" ADD COLUMN IF NOT EXISTS notify_broadcast BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,3 @@
+-- Subscriptions only work with the VAPID key they were made with, rotating keys flags the rest for re-registration
+ALTER TABLE "PushSubscriptions" ADD COLUMN IF NOT EXISTS vapid_key TEXT;
+ALTER TABLE "PushSubscriptions" ADD COLUMN IF NOT EXISTS needs_reregister BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- Where an imported record came from, e.g. an alliance partner or a backup. Live submissions leave it NULL
+ALTER TABLE "TeamMatches" ADD COLUMN IF NOT EXISTS source TEXT;
+ALTER TABLE "TeamEvents" ADD COLUMN IF NOT EXISTS source TEXT;
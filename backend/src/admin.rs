@@ -1,15 +1,21 @@
-use std::convert::Infallible;
-
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Form, Json};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
 
 use http::HeaderMap;
 
 use serde::{Deserialize, Serialize};
 
-use tracing::{error};
+use tracing::{error, info};
 
 use crate::{
+    error::ApiError,
     model::{self, AppState, Db, EventState, User},
+    ws::QueueSnapshot,
 };
 
 pub async fn get_user_helper(db: &Db, token: String) -> Result<Json<User>, (StatusCode, String)> {
@@ -30,11 +36,44 @@ pub async fn get_user_helper(db: &Db, token: String) -> Result<Json<User>, (Stat
     Ok(Json(user))
 }
 
-pub async fn get_current_match(State(state): State<AppState>) -> impl IntoResponse {
+pub async fn get_user_from_headers(
+    db: &Db,
+    headers: &HeaderMap,
+) -> Result<User, (StatusCode, String)> {
+    let token: String = match headers.get("x-access-token") {
+        Some(token) => match token.to_str() {
+            Ok(token) => token.to_string(),
+            Err(_) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "access_token was an invalid string".to_string(),
+                ))
+            }
+        },
+        None => return Err((StatusCode::UNAUTHORIZED, "No access code given".to_string())),
+    };
+
+    Ok(get_user_helper(db, token).await?.0)
+}
+
+/// The furthest queued match, null until one is queued for the current event
+pub async fn get_current_match(State(state): State<AppState>) -> Json<Option<String>> {
+    let manager = state.queue_manager.lock().await;
+    Json(manager.matches.last().cloned())
+}
+
+/// Who is online, queued and assigned, for a dashboard that was just loaded
+pub async fn get_queue_snapshot(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<QueueSnapshot>, (StatusCode, String)> {
+    check_admin_auth(&state.db, headers).await?;
+
     let manager = state.queue_manager.lock().await;
-    manager.matches[manager.matches.len() - 1].clone()
+    Ok(Json(manager.snapshot(&state)))
 }
 
+#[allow(dead_code)]
 pub async fn get_all_users(
     State(state): State<AppState>,
 ) -> Result<Json<Vec<User>>, (StatusCode, String)> {
@@ -81,34 +120,124 @@ pub async fn check_admin_auth(db: &Db, headers: HeaderMap) -> Result<(), (Status
 
 #[derive(Serialize, Deserialize)]
 pub struct NewEvent {
-    code: String,
     event_key: String,
     twitch_link: Option<String>,
+    #[serde(default)]
+    make_current: bool,
 }
 
 #[axum::debug_handler]
 pub async fn new_event(
     State(state): State<AppState>,
-    Form(event): Form<NewEvent>,
+    headers: HeaderMap,
+    Json(event): Json<NewEvent>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let user: User = match get_user_helper(&state.db, event.code).await {
-        Ok(user) => user.0,
-        Err(_) => return Err((StatusCode::UNAUTHORIZED, "User not in DB".to_string())),
-    };
-    if !user.is_admin {
-        return Err((StatusCode::UNAUTHORIZED, "User is not admin".to_string()));
-    }
-    match sqlx::query("INSERT INTO \"Events\" (event_key, steam_url) VALUES ($1, $2)")
-        .bind(event.event_key)
+    check_admin_auth(&state.db, headers).await?;
+
+    sqlx::query("INSERT INTO \"Events\" (event_key, steam_url) VALUES ($1, $2)")
+        .bind(event.event_key.clone())
         .bind(event.twitch_link)
         .execute(&state.db.pool)
         .await
+        .map_err(ApiError::sqlx)?;
+
+    if event.make_current {
+        set_current_event(&state, event.event_key).await?;
+    }
+
+    Ok(StatusCode::CREATED)
+}
+
+#[axum::debug_handler]
+pub async fn get_events(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<model::Event>>, (StatusCode, String)> {
+    match sqlx::query_as::<_, model::Event>(
+        "SELECT event_key, steam_url FROM \"Events\" ORDER BY event_key DESC",
+    )
+    .fetch_all(&state.db.pool)
+    .await
+    {
+        Ok(events) => Ok(Json(events)),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn get_current_event_handler(
+    State(state): State<AppState>,
+) -> Result<Json<EventState>, (StatusCode, String)> {
+    match sqlx::query_as::<_, EventState>("SELECT * FROM \"EventState\"")
+        .fetch_optional(&state.db.pool)
+        .await
     {
-        Ok(_) => Ok(()),
-        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        Ok(Some(event_state)) => Ok(Json(event_state)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "No current event set".to_string())),
+        Err(err) => Err(ApiError::sqlx(err)),
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SwitchEvent {
+    event_key: String,
+}
+
+#[axum::debug_handler]
+pub async fn switch_event(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(event): Json<SwitchEvent>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_admin_auth(&state.db, headers).await?;
+
+    set_current_event(&state, event.event_key).await?;
+
+    Ok(StatusCode::OK)
+}
+
+async fn replace_event_state(db: &Db, event_key: &str) -> Result<(), sqlx::Error> {
+    let mut tx = db.pool.begin().await?;
+    sqlx::query("DELETE FROM \"EventState\"")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO \"EventState\" (event_key, next_match, last_match) VALUES ($1, NULL, NULL)",
+    )
+    .bind(event_key)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Points EventState at another event, the match queue belongs to the old event so it is cleared
+async fn set_current_event(
+    state: &AppState,
+    event_key: String,
+) -> Result<(), (StatusCode, String)> {
+    let exists = match sqlx::query("SELECT 1 FROM \"Events\" WHERE event_key = $1")
+        .bind(event_key.clone())
+        .fetch_optional(&state.db.pool)
+        .await
+    {
+        Ok(row) => row.is_some(),
+        Err(err) => return Err(ApiError::sqlx(err)),
+    };
+    if !exists {
+        return Err((StatusCode::NOT_FOUND, format!("No event {event_key}")));
+    }
+
+    replace_event_state(&state.db, &event_key)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+    let mut manager = state.queue_manager.lock().await;
+    manager.matches.clear();
+    manager.assignments.clear();
+    info!("Switched current event to {}", event_key);
+
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UserPerm {
     admin: bool,
@@ -135,109 +264,156 @@ pub async fn set_user_permissions(
 #[axum::debug_handler]
 pub async fn get_finished_matches(
     State(state): State<AppState>,
-    Json(event_name): Json<String>,
-) -> Result<Json<Vec<String>>, impl IntoResponse> {
-    match sqlx::query_as::<_, model::TeamMatch>(
-        "SELECT * FROM \"TeamMatches\" WHERE event_key = $1",
+    Query(query): Query<EventQuery>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    let event_key = resolve_event(&state.db, query).await?;
+
+    match sqlx::query_scalar::<_, String>(
+        "SELECT DISTINCT match_key FROM \"TeamMatches\" WHERE event_key = $1 AND deleted_at IS NULL ORDER BY match_key",
     )
-    .bind(event_name)
+    .bind(event_key)
     .fetch_all(&state.db.pool)
     .await
     {
-        Ok(matches) => Ok(Json(
-            matches
-                .into_iter()
-                .map(|matches| matches.match_key)
-                .collect(),
-        )),
-        Err(err) => {
-            error!("Error fetching all matches: {}", err);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
-        }
+        Ok(matches) => Ok(Json(matches)),
+        Err(err) => Err(ApiError::sqlx(err)),
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ScoutStatsQuery {
+    event_key: Option<String>,
+    /// Only activity at or after this time counts
+    from: Option<DateTime<Utc>>,
+    /// Only activity before this time counts
+    to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ScoutStats {
+    pub id: String,
+    pub name: String,
+    pub matches_scouted: i64,
+    pub pits_scouted: i64,
+    pub photos_uploaded: i64,
+    /// Fraction of all match scouting in the range done by this scout
+    pub share: f64,
+    /// Consecutive matches scouted, ending at the latest scouted match
+    pub current_streak: i64,
+    pub longest_streak: i64,
+    pub last_active: Option<DateTime<Utc>>,
+}
+
+/// Leaderboard, scouts are ordered by matches scouted
 #[axum::debug_handler]
-pub async fn get_scouts_and_scouted(
+pub async fn get_scout_stats(
     State(state): State<AppState>,
-) -> Result<Json<(Vec<String>, Vec<i64>)>, (StatusCode, String)> {
-    let mut ret: (Vec<String>, Vec<i64>) = (vec![], vec![]);
-    let scouts: Vec<User> = match sqlx::query_as::<_, User>("SELECT * FROM \"Users\"")
-        .fetch_all(&state.db.pool)
-        .await
+    Query(query): Query<ScoutStatsQuery>,
+) -> Result<Json<Vec<ScoutStats>>, (StatusCode, String)> {
+    let event_key = resolve_event(
+        &state.db,
+        EventQuery {
+            event_key: query.event_key,
+        },
+    )
+    .await?;
+
+    // Matches are numbered in the order they were first scouted, a streak is a run of consecutive numbers
+    match sqlx::query_as::<_, ScoutStats>(
+        "WITH tm AS (SELECT scout_id, match_key, created_at FROM \"TeamMatches\" WHERE event_key = $1 AND deleted_at IS NULL AND ($2::TIMESTAMPTZ IS NULL OR created_at >= $2) AND ($3::TIMESTAMPTZ IS NULL OR created_at < $3)), \
+        matches AS (SELECT scout_id, COUNT(*) AS matches, MAX(created_at) AS last_at FROM tm GROUP BY scout_id), \
+        pits AS (SELECT scout_id, COUNT(*) AS pits, MAX(created_at) AS last_at FROM \"TeamEvents\" WHERE event_key = $1 AND deleted_at IS NULL AND ($2::TIMESTAMPTZ IS NULL OR created_at >= $2) AND ($3::TIMESTAMPTZ IS NULL OR created_at < $3) GROUP BY scout_id), \
+        photos AS (SELECT scout_id, COUNT(*) AS photos, MAX(created_at) AS last_at FROM images WHERE event_key = $1 AND ($2::TIMESTAMPTZ IS NULL OR created_at >= $2) AND ($3::TIMESTAMPTZ IS NULL OR created_at < $3) GROUP BY scout_id), \
+        played AS (SELECT match_key, ROW_NUMBER() OVER (ORDER BY MIN(created_at)) AS n FROM tm GROUP BY match_key), \
+        runs AS (SELECT s.scout_id, s.n, s.n - ROW_NUMBER() OVER (PARTITION BY s.scout_id ORDER BY s.n) AS run FROM (SELECT DISTINCT tm.scout_id, played.n FROM tm JOIN played ON played.match_key = tm.match_key) s), \
+        streaks AS (SELECT scout_id, COUNT(*) AS length, MAX(n) AS last_n FROM runs GROUP BY scout_id, run) \
+        SELECT u.id, u.name, \
+            COALESCE(m.matches, 0) AS matches_scouted, \
+            COALESCE(p.pits, 0) AS pits_scouted, \
+            COALESCE(ph.photos, 0) AS photos_uploaded, \
+            COALESCE(m.matches::FLOAT8 / NULLIF((SELECT COUNT(*) FROM tm), 0), 0) AS share, \
+            COALESCE((SELECT s.length FROM streaks s WHERE s.scout_id = u.id AND s.last_n = (SELECT MAX(n) FROM played)), 0) AS current_streak, \
+            COALESCE((SELECT MAX(s.length) FROM streaks s WHERE s.scout_id = u.id), 0) AS longest_streak, \
+            GREATEST(m.last_at, p.last_at, ph.last_at) AS last_active \
+        FROM \"Users\" u \
+        LEFT JOIN matches m ON m.scout_id = u.id \
+        LEFT JOIN pits p ON p.scout_id = u.id \
+        LEFT JOIN photos ph ON ph.scout_id = u.id \
+        ORDER BY matches_scouted DESC, u.name",
+    )
+    .bind(event_key)
+    .bind(query.from)
+    .bind(query.to)
+    .fetch_all(&state.db.pool)
+    .await
     {
-        Ok(scouts) => scouts,
-        Err(err) => {
-            error!("Failed to get scouts: {}", err);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to get scouts".to_string(),
-            ));
-        }
-    };
+        Ok(stats) => Ok(Json(stats)),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+/// Read endpoints take `?event_key=`, falling back on the current event
+#[derive(Debug, Deserialize)]
+pub struct EventQuery {
+    pub event_key: Option<String>,
+}
 
-    let total: i64 = match sqlx::query!("SELECT COUNT(*) FROM \"TeamMatches\"")
-        .fetch_one(&state.db.pool)
+pub async fn get_current_event(db: &Db) -> Result<String, (StatusCode, String)> {
+    match sqlx::query_as::<_, model::EventState>("SELECT * FROM \"EventState\"")
+        .fetch_optional(&db.pool)
         .await
     {
-        Ok(res) => res.count.unwrap_or(0),
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to select total user count from Db".to_string(),
-            ));
-        }
-    };
+        Ok(Some(event_state)) => Ok(event_state.event_key),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "No current event set".to_string())),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
 
-    for scout in scouts.iter() {
-        let id = scout.id.clone();
-        let name = scout.name.clone();
-        // TODO: Figure out how do COUNT commands without macros, because macros check for a db connection and are annoying for dev
-        let count: i64 = match sqlx::query!(
-            "SELECT COUNT(*) FROM \"TeamMatches\" WHERE scout_id = $1",
-            id
-        )
-        .fetch_one(&state.db.pool)
-        .await
-        {
-            Ok(res) => res.count.unwrap_or(0),
-            Err(_) => {
-                error!("User in queue not in Db");
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to select user from Db".to_string(),
-                ));
-            }
-        };
-        ret.0.push(name);
-        ret.1.push(count / total);
+pub async fn resolve_event(db: &Db, query: EventQuery) -> Result<String, (StatusCode, String)> {
+    match query.event_key {
+        Some(event_key) => Ok(event_key),
+        None => get_current_event(db).await,
     }
-    Ok(Json(ret))
 }
 
 #[axum::debug_handler]
 pub async fn get_unpitscouted_teams(
     State(state): State<AppState>,
-) -> Result<Json<Vec<model::Team>>, Infallible> {
-    let current_event = sqlx::query_as::<_, model::EventState>("SELECT* FROM \"EventState\"")
-        .fetch_one(&state.db.pool)
-        .await
-        .unwrap_or_else(|_| -> EventState {
-            error!("Failed to get eventstate, falling back on default");
-            model::EventState {
-                event_key: "2024orore".to_string(),
-                last_match: None,
-                next_match: None,
-            }
-        });
+    Query(query): Query<EventQuery>,
+) -> Result<Json<Vec<model::Team>>, (StatusCode, String)> {
+    let event_key = resolve_event(&state.db, query).await?;
 
     Ok(Json(
-        sqlx::query_as::<_, model::Team>(
-            "SELECT * FROM \"Teams\" WHERE event_key = $1 AND width = 0",
-        )
-        .bind(current_event.event_key)
-        .fetch_all(&state.db.pool)
-        .await
-        .unwrap_or(vec![]),
+        unpitscouted_teams(&state.db, &event_key)
+            .await
+            .unwrap_or(vec![]),
     ))
 }
+
+pub async fn unpitscouted_teams(db: &Db, event_key: &str) -> Result<Vec<model::Team>, sqlx::Error> {
+    sqlx::query_as::<_, model::Team>(
+        "SELECT t.* FROM \"Teams\" t LEFT JOIN \"TeamEvents\" te ON te.team_key = t.team_key AND te.event_key = t.event_key AND te.deleted_at IS NULL WHERE t.event_key = $1 AND (te.id IS NULL OR te.status <> 'complete')",
+    )
+    .bind(event_key)
+    .fetch_all(&db.pool)
+    .await
+}
+
+#[axum::debug_handler]
+pub async fn get_pit_status(
+    State(state): State<AppState>,
+    Query(query): Query<EventQuery>,
+) -> Result<Json<Vec<model::TeamPitStatus>>, (StatusCode, String)> {
+    let event_key = resolve_event(&state.db, query).await?;
+
+    match sqlx::query_as::<_, model::TeamPitStatus>(
+        "SELECT t.team_key, t.nickname, COALESCE(te.status, 'not_started') AS status FROM \"Teams\" t LEFT JOIN \"TeamEvents\" te ON te.team_key = t.team_key AND te.event_key = t.event_key AND te.deleted_at IS NULL WHERE t.event_key = $1 ORDER BY t.team_key",
+    )
+    .bind(event_key)
+    .fetch_all(&state.db.pool)
+    .await
+    {
+        Ok(statuses) => Ok(Json(statuses)),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
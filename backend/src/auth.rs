@@ -1,4 +1,7 @@
-use crate::model::{self, AppState};
+use crate::{
+    events::SseReturn,
+    model::{self, AppState},
+};
 use axum::{
     extract::{Json, Query, State},
     http::StatusCode,
@@ -87,9 +90,6 @@ pub async fn slack_callback(
         name.to_string(),
         false,
         false,
-        None,
-        None,
-        None,
         access_token.clone().replace("\"", ""),
     );
 
@@ -110,7 +110,8 @@ pub async fn slack_callback(
             }
         };
 
-    let _id = insert_user(profile.clone(), state.db).await?;
+    insert_user(profile.clone(), state.db).await?;
+    state.events.publish(SseReturn::LoggedInScout(name.to_string()));
 
     Ok(axum::http::Response::builder()
         .status(StatusCode::SEE_OTHER)
@@ -148,6 +149,7 @@ pub async fn slack_callback(
     //);
 }
 
+#[allow(dead_code)]
 pub async fn logout(
     State(state): State<model::AppState>,
     Json(access_token): Json<String>,
@@ -170,27 +172,19 @@ pub async fn logout(
         .header(LOCATION, frontend_url) 
         .header(
             SET_COOKIE,
-            format!(
-                "access_token=deleted; Path=/; SameSite=None; expires=Thu, 01 Jan 1970 00:00:00 GMT"
-            ),
+            "access_token=deleted; Path=/; SameSite=None; expires=Thu, 01 Jan 1970 00:00:00 GMT",
         )
         .header(
             SET_COOKIE,
-            format!(
-                "scout_name=deleted; Path=/; SameSite=None; expires=Thu, 01 Jan 1970 00:00:00 GMT"
-            ),
+            "scout_name=deleted; Path=/; SameSite=None; expires=Thu, 01 Jan 1970 00:00:00 GMT",
         )
         .header(
             SET_COOKIE,
-            format!(
-                "scout_id=deleted; Path=/; SameSite=None; expires=Thu, 01 Jan 1970 00:00:00 GMT"
-            ),
+            "scout_id=deleted; Path=/; SameSite=None; expires=Thu, 01 Jan 1970 00:00:00 GMT",
         )
         .header(
             SET_COOKIE,
-            format!(
-                "current_event_key=deleted; Path=/; SameSite=None; expires=Thu, 01 Jan 1970 00:00:00 GMT"
-            ),
+            "current_event_key=deleted; Path=/; SameSite=None; expires=Thu, 01 Jan 1970 00:00:00 GMT",
         )
         .body("Redirecting...".to_string())
         .unwrap();
@@ -218,7 +212,7 @@ pub async fn check_auth(
     info!("All Users: {:?}", users);
 
     let user: model::User = match sqlx::query_as("SELECT * FROM \"Users\" WHERE access_token = $1")
-        .bind(format!("{}", req.access_token))
+        .bind(req.access_token.to_string())
         .fetch_optional(&state.db.pool)
         .await
     {
@@ -256,14 +250,11 @@ async fn insert_user(profile: model::User, db: model::Db) -> Result<(), (StatusC
     info!("New user\n{:?}", profile);
 
     if let Err(e) =
-        sqlx::query("INSERT INTO \"Users\" (id, name, is_notify, is_admin, endpoint, p256dh, auth, access_token) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT(id) DO NOTHING")
+        sqlx::query("INSERT INTO \"Users\" (id, name, is_notify, is_admin, access_token) VALUES ($1, $2, $3, $4, $5) ON CONFLICT(id) DO NOTHING")
             .bind(profile.id.clone())
             .bind(profile.name.clone())
             .bind(profile.is_notify)
             .bind(profile.is_admin)
-            .bind(profile.endpoint)
-            .bind(profile.p256dh)
-            .bind(profile.auth)
             .bind(profile.access_token)
             .execute(&db.pool)
             .await
@@ -277,6 +268,7 @@ async fn insert_user(profile: model::User, db: model::Db) -> Result<(), (StatusC
     Ok(())
 }
 
+#[allow(dead_code)]
 async fn get_user(access_token: String, db: &model::Db) -> Result<model::User, Redirect> {
     //let cookie = jar.get("sid");
     //info!("Access token: {:?}", cookie);
@@ -0,0 +1,153 @@
+use axum::{extract::State, http::StatusCode, Extension, Json};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use socketioxide::SocketIo;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    admin::get_user_from_headers,
+    error::ApiError,
+    events::SseReturn,
+    model::{AppState, User},
+    webpush::PushMessage,
+    ws,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Scout,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Recipients {
+    All,
+    Role { role: Role },
+    Users { ids: Vec<String> },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewBroadcast {
+    pub title: String,
+    pub body: String,
+    pub recipients: Recipients,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BroadcastSent {
+    pub id: String,
+    pub recipients: usize,
+}
+
+const MAX_TITLE_LENGTH: usize = 100;
+const MAX_BODY_LENGTH: usize = 1000;
+
+async fn find_recipients(
+    state: &AppState,
+    recipients: Recipients,
+) -> Result<Vec<User>, sqlx::Error> {
+    let users = match recipients {
+        Recipients::All => sqlx::query_as("SELECT * FROM \"Users\""),
+        Recipients::Role { role: Role::Admin } => {
+            sqlx::query_as("SELECT * FROM \"Users\" WHERE is_admin")
+        }
+        Recipients::Role { role: Role::Scout } => {
+            sqlx::query_as("SELECT * FROM \"Users\" WHERE NOT is_admin")
+        }
+        Recipients::Users { ids } => {
+            sqlx::query_as("SELECT * FROM \"Users\" WHERE id = ANY($1)").bind(ids)
+        }
+    };
+
+    users.fetch_all(&state.db.pool).await
+}
+
+/// Announces something to scouts over the socket and web push, e.g. "all scouts to the pits".
+/// Responds once the message is queued, how it reached each scout follows on the admin SSE stream.
+#[axum::debug_handler]
+pub async fn send_broadcast(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    headers: HeaderMap,
+    Json(broadcast): Json<NewBroadcast>,
+) -> Result<(StatusCode, Json<BroadcastSent>), (StatusCode, String)> {
+    let admin = get_user_from_headers(&state.db, &headers).await?;
+    if !admin.is_admin {
+        return Err((StatusCode::UNAUTHORIZED, "Not an admin".to_string()));
+    }
+
+    if broadcast.title.trim().is_empty()
+        || broadcast.title.len() > MAX_TITLE_LENGTH
+        || broadcast.body.len() > MAX_BODY_LENGTH
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Broadcasts need a title up to {MAX_TITLE_LENGTH} characters and a body up to {MAX_BODY_LENGTH}"),
+        ));
+    }
+
+    let recipients = find_recipients(&state, broadcast.recipients)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+    let id = Uuid::new_v4().to_string();
+    let message = PushMessage::Broadcast {
+        id: id.clone(),
+        title: broadcast.title,
+        body: broadcast.body,
+    };
+    let payload = json!(message);
+
+    let sids = {
+        let manager = state.queue_manager.lock().await;
+        recipients
+            .iter()
+            .map(|user| manager.sid_of(&user.id))
+            .collect::<Vec<_>>()
+    };
+
+    for (user, sid) in recipients.iter().cloned().zip(sids) {
+        let socket = match (sid, io.of(ws::SCOUT_NS)) {
+            (Some(sid), Some(namespace)) => namespace
+                .to(sid)
+                .emit("broadcast", payload.clone())
+                .map_err(|err| error!("Failed to send broadcast to {}: {}", user.name, err))
+                .is_ok(),
+            _ => false,
+        };
+
+        let state = state.clone();
+        let message = message.clone();
+        let broadcast_id = id.clone();
+        tokio::spawn(async move {
+            let push = state.push.deliver_to_user(&user, message).await;
+
+            state.events.publish(SseReturn::BroadcastDelivery {
+                broadcast_id,
+                user_id: user.id,
+                scout_name: user.name,
+                socket,
+                push,
+            });
+        });
+    }
+
+    info!(
+        "{} broadcast {} to {} users",
+        admin.name,
+        id,
+        recipients.len()
+    );
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(BroadcastSent {
+            id,
+            recipients: recipients.len(),
+        }),
+    ))
+}
@@ -0,0 +1,132 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+};
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use futures::TryStreamExt;
+use web_push_native::jwt_simple::algorithms::ES256KeyPair;
+
+use crate::{
+    export::{self, Dataset, ExportFilter, ExportFormat},
+    import::{self, ImportOptions, Mapping},
+    model::Db,
+    season::Seasons,
+    webpush::public_key_of,
+};
+
+/// Commands run instead of the server, e.g. `backend vapid-keygen`
+pub async fn run(command: &str, args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        "vapid-keygen" => {
+            vapid_keygen();
+            Ok(())
+        }
+        "export" => export(args).await,
+        "import" => import(args).await,
+        _ => Err(
+            format!("Unknown command {command}, expected vapid-keygen, export or import").into(),
+        ),
+    }
+}
+
+/// Prints a new key pair in `.env` format. Swapping in a new pair flags existing subscriptions
+/// at the next startup, and scouts' browsers subscribe again the next time they open the app.
+fn vapid_keygen() {
+    let key_pair = ES256KeyPair::generate();
+
+    println!("VAPID_PUBLIC=\"{}\"", public_key_of(&key_pair));
+    println!(
+        "VAPID_PRIVATE=\"{}\"",
+        Base64UrlUnpadded::encode_string(&key_pair.to_bytes())
+    );
+}
+
+/// The database and season configs, as the server would load them
+async fn connect() -> Result<(Db, Seasons), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let db = Db::new(std::env::var("DATABASE_URL")?).await?;
+    let seasons_dir = std::env::var("SEASONS_DIR").unwrap_or("seasons".to_string());
+    let seasons = Seasons::load(&seasons_dir)?;
+
+    Ok((db, seasons))
+}
+
+/// Value following a `--flag`
+fn flag(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+        .cloned()
+}
+
+/// `backend export <matches|pits|teams|images> --event <key> [--team <key>] [--from <n>] [--to <n>]
+/// [--format csv|json] [--out <file>]`, written to stdout without `--out`
+async fn export(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let dataset: Dataset = args
+        .first()
+        .ok_or("Missing dataset, expected matches, pits, teams or images")?
+        .parse()?;
+    let format: ExportFormat = flag(&args, "--format")
+        .map(|format| format.parse())
+        .transpose()?
+        .unwrap_or_default();
+    let filter = ExportFilter {
+        event_key: flag(&args, "--event").ok_or("Missing --event")?,
+        team_key: flag(&args, "--team"),
+        from_match: flag(&args, "--from").map(|n| n.parse()).transpose()?,
+        to_match: flag(&args, "--to").map(|n| n.parse()).transpose()?,
+    };
+
+    let (db, seasons) = connect().await?;
+
+    let mut out: Box<dyn Write> = match flag(&args, "--out") {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    let mut chunks = Box::pin(export::export(db, &seasons, dataset, filter, format));
+    while let Some(chunk) = chunks.try_next().await? {
+        out.write_all(&chunk)?;
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+/// `backend import <matches|pits> --file <path> --source <name> --user <importer id> [--mapping <file>]
+/// [--format csv|json] [--event <key>] [--on-conflict skip|replace|keep] [--dry-run]`, prints the report
+async fn import(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let dataset: Dataset = args
+        .first()
+        .ok_or("Missing dataset, expected matches or pits")?
+        .parse()?;
+    let format: ExportFormat = flag(&args, "--format")
+        .map(|format| format.parse())
+        .transpose()?
+        .unwrap_or_default();
+    let mapping: Option<Mapping> = flag(&args, "--mapping")
+        .map(|path| -> Result<Mapping, Box<dyn std::error::Error>> {
+            Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+        })
+        .transpose()?;
+    let options = ImportOptions {
+        source: flag(&args, "--source").ok_or("Missing --source")?,
+        importer_id: flag(&args, "--user").ok_or("Missing --user")?,
+        event_key: flag(&args, "--event"),
+        on_conflict: flag(&args, "--on-conflict")
+            .map(|policy| policy.parse())
+            .transpose()?
+            .unwrap_or_default(),
+        dry_run: args.iter().any(|arg| arg == "--dry-run"),
+    };
+
+    let file = fs::read(flag(&args, "--file").ok_or("Missing --file")?)?;
+    let rows = import::parse(&file, format, mapping.as_ref())?;
+
+    let (db, seasons) = connect().await?;
+    let report = import::import_rows(&db, &seasons, dataset, rows, &options).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
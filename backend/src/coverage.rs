@@ -0,0 +1,182 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use http::HeaderMap;
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::{
+    admin::{check_admin_auth, resolve_event, EventQuery},
+    error::ApiError,
+    events::SseReturn,
+    model::{self, AppState, Db},
+};
+
+/// How long scouts have to submit after the next match is queued before a gap is reported
+const SUBMISSION_GRACE: Duration = Duration::from_secs(180);
+
+/// One robot slot from the schedule and how many scouting records it has
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct SlotCoverage {
+    #[serde(skip)]
+    pub match_key: String,
+    pub slot: String,
+    pub team_key: String,
+    pub records: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MatchCoverage {
+    pub match_key: String,
+    /// Queued or scouted, matches that haven't been played can't be missing anything
+    pub played: bool,
+    pub robots: Vec<SlotCoverage>,
+    pub missing: Vec<String>,
+    pub duplicates: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TeamCoverage {
+    pub team_key: String,
+    pub played_matches: i64,
+    pub scouted_matches: i64,
+    pub coverage: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CoverageReport {
+    pub event_key: String,
+    pub matches: Vec<MatchCoverage>,
+    pub teams: Vec<TeamCoverage>,
+}
+
+/// The event's schedule split into red_1..blue_3 slots, in match order
+async fn slot_coverage(
+    db: &Db,
+    event_key: &str,
+    match_key: Option<&str>,
+) -> Result<Vec<SlotCoverage>, sqlx::Error> {
+    sqlx::query_as::<_, SlotCoverage>(
+        "WITH counts AS (SELECT match_key, team_key, COUNT(*) AS records FROM \"TeamMatches\" WHERE event_key = $1 AND deleted_at IS NULL GROUP BY match_key, team_key) \
+        SELECT m.match_key, s.slot, s.team_key, COALESCE(counts.records, 0) AS records \
+        FROM \"TBAMatches\" m \
+        CROSS JOIN LATERAL (VALUES ('red_1', m.red_1), ('red_2', m.red_2), ('red_3', m.red_3), ('blue_1', m.blue_1), ('blue_2', m.blue_2), ('blue_3', m.blue_3)) AS s(slot, team_key) \
+        LEFT JOIN counts ON counts.match_key = m.match_key AND counts.team_key = s.team_key \
+        WHERE m.event_key = $1 AND ($2::TEXT IS NULL OR m.match_key = $2) \
+        ORDER BY m.time, m.match_key, s.slot",
+    )
+    .bind(event_key)
+    .bind(match_key)
+    .fetch_all(&db.pool)
+    .await
+}
+
+fn missing_robots(robots: &[SlotCoverage]) -> Vec<String> {
+    robots
+        .iter()
+        .filter(|robot| robot.records == 0)
+        .map(|robot| robot.team_key.clone())
+        .collect()
+}
+
+#[axum::debug_handler]
+pub async fn get_coverage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<EventQuery>,
+) -> Result<Json<CoverageReport>, (StatusCode, String)> {
+    check_admin_auth(&state.db, headers).await?;
+    let event_key = resolve_event(&state.db, query).await?;
+
+    let slots = slot_coverage(&state.db, &event_key, None)
+        .await
+        .map_err(ApiError::sqlx)?;
+    let queued = state.queue_manager.lock().await.matches.clone();
+
+    let mut matches: Vec<MatchCoverage> = vec![];
+    for slot in slots {
+        match matches.last_mut() {
+            Some(last) if last.match_key == slot.match_key => last.robots.push(slot),
+            _ => matches.push(MatchCoverage {
+                match_key: slot.match_key.clone(),
+                played: false,
+                robots: vec![slot],
+                missing: vec![],
+                duplicates: vec![],
+            }),
+        }
+    }
+
+    let mut teams: BTreeMap<String, (i64, i64)> = BTreeMap::new();
+    for coverage in matches.iter_mut() {
+        coverage.played = queued.contains(&coverage.match_key)
+            || coverage.robots.iter().any(|robot| robot.records > 0);
+        coverage.duplicates = coverage
+            .robots
+            .iter()
+            .filter(|robot| robot.records > 1)
+            .map(|robot| robot.team_key.clone())
+            .collect();
+
+        if !coverage.played {
+            continue;
+        }
+        coverage.missing = missing_robots(&coverage.robots);
+
+        for robot in coverage.robots.iter() {
+            let (played, scouted) = teams.entry(robot.team_key.clone()).or_default();
+            *played += 1;
+            if robot.records > 0 {
+                *scouted += 1;
+            }
+        }
+    }
+
+    let teams = teams
+        .into_iter()
+        .map(
+            |(team_key, (played_matches, scouted_matches))| TeamCoverage {
+                team_key,
+                played_matches,
+                scouted_matches,
+                coverage: scouted_matches as f64 / played_matches as f64,
+            },
+        )
+        .collect();
+
+    Ok(Json(CoverageReport {
+        event_key,
+        matches,
+        teams,
+    }))
+}
+
+/// Called once the match after `match_key` is queued, tells the dashboard about robots nobody scouted
+pub fn check_when_complete(state: AppState, match_key: String) {
+    tokio::spawn(async move {
+        tokio::time::sleep(SUBMISSION_GRACE).await;
+
+        let event_key = model::event_key_of(&match_key);
+        let robots = match slot_coverage(&state.db, event_key, Some(&match_key)).await {
+            Ok(robots) => robots,
+            Err(err) => {
+                error!("Failed to check coverage of {}: {}", match_key, err);
+                return;
+            }
+        };
+
+        let missing = missing_robots(&robots);
+        if missing.is_empty() {
+            return;
+        }
+
+        info!("{} has unscouted robots: {:?}", match_key, missing);
+        state
+            .events
+            .publish(SseReturn::CoverageGap { match_key, missing });
+    });
+}
@@ -0,0 +1,154 @@
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+use serde::Serialize;
+
+use crate::{
+    admin::{check_admin_auth, unpitscouted_teams},
+    error::ApiError,
+    model::{AppState, EventState, Team},
+    ws::QueueSnapshot,
+};
+
+/// Submissions shown in the dashboard's activity feed
+const RECENT_SUBMISSIONS: i64 = 20;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RobotStatus {
+    /// No scout was free when the match was queued
+    Waiting,
+    Assigned,
+    Scouted,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RobotScouting {
+    pub team_key: String,
+    pub color: Option<String>,
+    pub scout_name: Option<String>,
+    pub status: RobotStatus,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct RecentSubmission {
+    pub match_key: String,
+    pub team_key: String,
+    pub scout_name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Everything the admin page shows, so a reload starts where it left off
+#[derive(Debug, Serialize)]
+pub struct Dashboard {
+    pub event_key: String,
+    pub current_match: Option<String>,
+    pub next_match: Option<String>,
+    pub robots: Vec<RobotScouting>,
+    pub queue: QueueSnapshot,
+    pub unpitscouted: Vec<Team>,
+    pub recent_submissions: Vec<RecentSubmission>,
+}
+
+#[axum::debug_handler]
+pub async fn get_dashboard(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Dashboard>, (StatusCode, String)> {
+    check_admin_auth(&state.db, headers).await?;
+
+    let event_state = match sqlx::query_as::<_, EventState>("SELECT * FROM \"EventState\"")
+        .fetch_optional(&state.db.pool)
+        .await
+    {
+        Ok(Some(event_state)) => event_state,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "No current event set".to_string())),
+        Err(err) => return Err(ApiError::sqlx(err)),
+    };
+
+    // Copied out so the queue isn't locked while the database is queried
+    let (queue, current_match, waiting) = {
+        let manager = state.queue_manager.lock().await;
+        let current_match = manager.matches.last().cloned().or(event_state.last_match);
+        let waiting = current_match
+            .as_deref()
+            .map(|match_key| manager.unassigned_robots(match_key))
+            .unwrap_or_default();
+        (manager.snapshot(&state), current_match, waiting)
+    };
+
+    let mut robots: Vec<RobotScouting> = vec![];
+    if let Some(match_key) = &current_match {
+        // A team scouted twice is listed once, by whoever submitted last
+        let scouted = sqlx::query_as::<_, (String, String)>(
+            "SELECT DISTINCT ON (tm.team_key) tm.team_key, u.name FROM \"TeamMatches\" tm JOIN \"Users\" u ON u.id = tm.scout_id WHERE tm.match_key = $1 AND tm.deleted_at IS NULL ORDER BY tm.team_key, tm.created_at DESC",
+        )
+        .bind(match_key)
+        .fetch_all(&state.db.pool)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+        for (team_key, scout_name) in scouted {
+            robots.push(RobotScouting {
+                team_key,
+                color: None,
+                scout_name: Some(scout_name),
+                status: RobotStatus::Scouted,
+            });
+        }
+
+        for assignment in queue
+            .assigned
+            .iter()
+            .filter(|assignment| assignment.match_key == *match_key)
+        {
+            if robots
+                .iter()
+                .any(|robot| robot.team_key == assignment.team_key)
+            {
+                continue;
+            }
+            robots.push(RobotScouting {
+                team_key: assignment.team_key.clone(),
+                color: Some(assignment.color.clone()),
+                scout_name: Some(assignment.scout_name.clone()),
+                status: RobotStatus::Assigned,
+            });
+        }
+
+        for (team_key, color) in waiting {
+            if robots.iter().any(|robot| robot.team_key == team_key) {
+                continue;
+            }
+            robots.push(RobotScouting {
+                team_key,
+                color: Some(color),
+                scout_name: None,
+                status: RobotStatus::Waiting,
+            });
+        }
+    }
+
+    let unpitscouted = unpitscouted_teams(&state.db, &event_state.event_key)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+    let recent_submissions = sqlx::query_as::<_, RecentSubmission>(
+        "SELECT tm.match_key, tm.team_key, u.name AS scout_name, tm.created_at FROM \"TeamMatches\" tm JOIN \"Users\" u ON u.id = tm.scout_id WHERE tm.event_key = $1 AND tm.deleted_at IS NULL ORDER BY tm.created_at DESC LIMIT $2",
+    )
+    .bind(&event_state.event_key)
+    .bind(RECENT_SUBMISSIONS)
+    .fetch_all(&state.db.pool)
+    .await
+    .map_err(ApiError::sqlx)?;
+
+    Ok(Json(Dashboard {
+        event_key: event_state.event_key,
+        current_match,
+        next_match: event_state.next_match,
+        robots,
+        queue,
+        unpitscouted,
+        recent_submissions,
+    }))
+}
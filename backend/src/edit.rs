@@ -0,0 +1,404 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use http::HeaderMap;
+use serde_json::Value;
+use sqlx::{postgres::PgArguments, query::QueryScalar, Postgres, Transaction};
+use tracing::info;
+
+use crate::{
+    admin::{check_admin_auth, get_user_from_headers},
+    error::ApiError,
+    model::{event_key_of, AppState, Db, Revision, TeamEvent, TeamMatch, User},
+    submit::validate_team_match,
+};
+
+const TEAM_MATCHES: &str = "TeamMatches";
+pub const TEAM_EVENTS: &str = "TeamEvents";
+
+type RecordQuery = QueryScalar<'static, Postgres, Value, PgArguments>;
+
+// Scouts can only fix their own submissions for a short time, admins can edit anything
+fn edit_window() -> Duration {
+    let minutes: i64 = std::env::var("EDIT_WINDOW_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or(30);
+
+    Duration::minutes(minutes)
+}
+
+fn can_edit(user: &User, scout_id: &str, created_at: DateTime<Utc>) -> bool {
+    user.is_admin || (user.id == scout_id && Utc::now() - created_at <= edit_window())
+}
+
+/// Locks a live record for the rest of the transaction, returning its current value, scout and submission time
+async fn lock_record(
+    tx: &mut Transaction<'_, Postgres>,
+    table: &str,
+    id: i64,
+) -> Result<(Value, String, DateTime<Utc>), (StatusCode, String)> {
+    let query = format!(
+        "SELECT to_jsonb(t), t.scout_id, t.created_at FROM \"{table}\" t WHERE t.id = $1 AND t.deleted_at IS NULL FOR UPDATE"
+    );
+
+    match sqlx::query_as(&query)
+        .bind(id)
+        .fetch_optional(&mut **tx)
+        .await
+    {
+        Ok(Some(record)) => Ok(record),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("No record {id} in {table}"))),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+/// Stores a record as it was before and after a change
+pub async fn record_revision(
+    tx: &mut Transaction<'_, Postgres>,
+    table: &str,
+    id: i64,
+    action: &str,
+    editor_id: &str,
+    old_value: Value,
+    new_value: Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO \"Revisions\" (table_name, record_id, action, editor_id, old_value, new_value) VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(table)
+        .bind(id)
+        .bind(action)
+        .bind(editor_id)
+        .bind(old_value)
+        .bind(new_value)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Runs an update against a single record, query must return the updated row as `to_jsonb`
+async fn edit_record(
+    state: &AppState,
+    headers: &HeaderMap,
+    table: &str,
+    id: i64,
+    action: &str,
+    query: RecordQuery,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let user = get_user_from_headers(&state.db, headers).await?;
+
+    apply_edit(&state.db, &user, table, id, action, query)
+        .await
+        .map(Json)
+}
+
+async fn apply_edit(
+    db: &Db,
+    user: &User,
+    table: &str,
+    id: i64,
+    action: &str,
+    query: RecordQuery,
+) -> Result<Value, (StatusCode, String)> {
+    let mut tx = db.pool.begin().await.map_err(ApiError::sqlx)?;
+
+    let (old_value, scout_id, created_at) = lock_record(&mut tx, table, id).await?;
+
+    if !can_edit(user, &scout_id, created_at) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Scouts can only edit their own recent submissions".to_string(),
+        ));
+    }
+
+    let new_value = query.fetch_one(&mut *tx).await.map_err(ApiError::sqlx)?;
+
+    record_revision(
+        &mut tx,
+        table,
+        id,
+        action,
+        &user.id,
+        old_value,
+        new_value.clone(),
+    )
+    .await
+    .map_err(ApiError::sqlx)?;
+
+    tx.commit().await.map_err(ApiError::sqlx)?;
+
+    info!("{} applied {} to {} {}", user.name, action, table, id);
+
+    Ok(new_value)
+}
+
+async fn get_revisions(
+    state: &AppState,
+    headers: HeaderMap,
+    table: &str,
+    id: i64,
+) -> Result<Json<Vec<Revision>>, (StatusCode, String)> {
+    check_admin_auth(&state.db, headers).await?;
+
+    match sqlx::query_as::<_, Revision>(
+        "SELECT * FROM \"Revisions\" WHERE table_name = $1 AND record_id = $2 ORDER BY edited_at",
+    )
+    .bind(table)
+    .bind(id)
+    .fetch_all(&state.db.pool)
+    .await
+    {
+        Ok(revisions) => Ok(Json(revisions)),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn update_team_match(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+    Json(form): Json<TeamMatch>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let (season, data) = validate_team_match(&state, &form)?;
+    let event_key = event_key_of(&form.match_key).to_string();
+
+    // scout_id is left alone so the record stays attributed to whoever scouted it
+    let query: RecordQuery = sqlx::query_scalar("UPDATE \"TeamMatches\" t SET match_key = $2, team_key = $3, event_key = $4, season = $5, data = $6 WHERE t.id = $1 RETURNING to_jsonb(t)").bind(id).bind(form.match_key).bind(form.team_key).bind(event_key).bind(season).bind(Value::from(data));
+
+    edit_record(&state, &headers, TEAM_MATCHES, id, "update", query).await
+}
+
+#[axum::debug_handler]
+pub async fn delete_team_match(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let query: RecordQuery = sqlx::query_scalar(
+        "UPDATE \"TeamMatches\" t SET deleted_at = now() WHERE t.id = $1 RETURNING to_jsonb(t)",
+    )
+    .bind(id);
+
+    edit_record(&state, &headers, TEAM_MATCHES, id, "delete", query).await
+}
+
+#[axum::debug_handler]
+pub async fn get_team_match_revisions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<Revision>>, (StatusCode, String)> {
+    get_revisions(&state, headers, TEAM_MATCHES, id).await
+}
+
+#[axum::debug_handler]
+pub async fn update_pit_data(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+    Json(form): Json<TeamEvent>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let query: RecordQuery = sqlx::query_scalar("UPDATE \"TeamEvents\" t SET team_key = $2, event_key = $3, width = $4, length = $5, is_short = $6, is_camera = $7, drivetrain_enum = $8, is_ground_intake = $9, is_chute_intake = $10, polish = $11 WHERE t.id = $1 RETURNING to_jsonb(t)").bind(id).bind(form.team_key).bind(form.event_key).bind(form.width).bind(form.length).bind(form.is_short).bind(form.is_camera).bind(form.drivetrain).bind(form.is_ground_intake).bind(form.is_chute_intake).bind(form.polish);
+
+    edit_record(&state, &headers, TEAM_EVENTS, id, "update", query).await
+}
+
+#[axum::debug_handler]
+pub async fn delete_pit_data(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let query: RecordQuery = sqlx::query_scalar(
+        "UPDATE \"TeamEvents\" t SET deleted_at = now() WHERE t.id = $1 RETURNING to_jsonb(t)",
+    )
+    .bind(id);
+
+    edit_record(&state, &headers, TEAM_EVENTS, id, "delete", query).await
+}
+
+#[axum::debug_handler]
+pub async fn get_pit_data_revisions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<Revision>>, (StatusCode, String)> {
+    get_revisions(&state, headers, TEAM_EVENTS, id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::submit::submit_pit_data;
+
+    fn user(id: &str, is_admin: bool) -> User {
+        User::new(
+            id.to_string(),
+            id.to_string(),
+            false,
+            is_admin,
+            id.to_string(),
+        )
+    }
+
+    #[test]
+    fn scouts_only_edit_their_own_recent_submissions() {
+        let ada = user("ada", false);
+        let recent = Utc::now() - edit_window() + Duration::minutes(1);
+        let stale = Utc::now() - edit_window() - Duration::minutes(1);
+
+        assert!(can_edit(&ada, "ada", recent));
+        assert!(!can_edit(&ada, "ada", stale));
+        assert!(!can_edit(&ada, "bob", recent));
+        assert!(can_edit(&user("admin", true), "bob", stale));
+    }
+
+    /// A database with an admin and two scouts, None when no test database is configured
+    async fn setup() -> Option<Db> {
+        let db = Db::for_test().await?;
+        sqlx::query("INSERT INTO \"Users\" (id, name, is_notify, is_admin, access_token) VALUES ('admin', 'Admin', FALSE, TRUE, 'a'), ('ada', 'Ada', FALSE, FALSE, 'b'), ('bob', 'Bob', FALSE, FALSE, 'c')")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        Some(db)
+    }
+
+    async fn revisions(db: &Db, table: &str, id: i64) -> Vec<Revision> {
+        sqlx::query_as(
+            "SELECT * FROM \"Revisions\" WHERE table_name = $1 AND record_id = $2 ORDER BY id",
+        )
+        .bind(table)
+        .bind(id)
+        .fetch_all(&db.pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn edits_keep_the_record_before_and_after() {
+        let Some(db) = setup().await else {
+            return;
+        };
+        let id: i64 = sqlx::query_scalar("INSERT INTO \"TeamMatches\" (match_key, team_key, event_key, season, data, scout_id) VALUES ('2024casj_qm1', 'frc254', '2024casj', 2024, '{\"speaker\": 1}', 'ada') RETURNING id::BIGINT")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        let update = |speaker: i64| -> RecordQuery {
+            sqlx::query_scalar(
+                "UPDATE \"TeamMatches\" t SET data = $2 WHERE t.id = $1 RETURNING to_jsonb(t)",
+            )
+            .bind(id)
+            .bind(serde_json::json!({ "speaker": speaker }))
+        };
+
+        let forbidden = apply_edit(
+            &db,
+            &user("bob", false),
+            TEAM_MATCHES,
+            id,
+            "update",
+            update(9),
+        )
+        .await;
+        assert_eq!(forbidden.unwrap_err().0, StatusCode::FORBIDDEN);
+
+        apply_edit(
+            &db,
+            &user("ada", false),
+            TEAM_MATCHES,
+            id,
+            "update",
+            update(2),
+        )
+        .await
+        .unwrap();
+        let delete: RecordQuery = sqlx::query_scalar(
+            "UPDATE \"TeamMatches\" t SET deleted_at = now() WHERE t.id = $1 RETURNING to_jsonb(t)",
+        )
+        .bind(id);
+        apply_edit(
+            &db,
+            &user("admin", true),
+            TEAM_MATCHES,
+            id,
+            "delete",
+            delete,
+        )
+        .await
+        .unwrap();
+
+        let history = revisions(&db, TEAM_MATCHES, id).await;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].action, "update");
+        assert_eq!(history[0].editor_id, "ada");
+        assert_eq!(history[0].old_value.as_ref().unwrap()["data"]["speaker"], 1);
+        assert_eq!(history[0].new_value.as_ref().unwrap()["data"]["speaker"], 2);
+        assert_eq!(history[1].action, "delete");
+        assert!(history[1].old_value.as_ref().unwrap()["deleted_at"].is_null());
+        assert!(!history[1].new_value.as_ref().unwrap()["deleted_at"].is_null());
+
+        // Deleted records can't be edited again
+        let gone = apply_edit(
+            &db,
+            &user("admin", true),
+            TEAM_MATCHES,
+            id,
+            "update",
+            update(3),
+        )
+        .await;
+        assert_eq!(gone.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        db.drop_test().await;
+    }
+
+    #[tokio::test]
+    async fn pit_visits_over_an_existing_record_are_revisions() {
+        let Some(db) = setup().await else {
+            return;
+        };
+        let visit = |scout_id: &str, width: Option<i16>, length: Option<i16>| TeamEvent {
+            id: 0,
+            team_key: "frc254".to_string(),
+            event_key: "2024casj".to_string(),
+            width,
+            length,
+            is_short: None,
+            is_camera: None,
+            drivetrain: None,
+            is_ground_intake: None,
+            is_chute_intake: None,
+            polish: None,
+            scout_id: scout_id.to_string(),
+        };
+
+        let first = submit_pit_data(&db, visit("ada", Some(20), None))
+            .await
+            .unwrap();
+        assert!(revisions(&db, TEAM_EVENTS, first.id).await.is_empty());
+
+        let second = submit_pit_data(&db, visit("bob", None, Some(30)))
+            .await
+            .unwrap();
+        assert_eq!(second.id, first.id);
+
+        let history = revisions(&db, TEAM_EVENTS, first.id).await;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].editor_id, "bob");
+        let (old_value, new_value) = (
+            history[0].old_value.as_ref().unwrap(),
+            history[0].new_value.as_ref().unwrap(),
+        );
+        assert!(old_value["length"].is_null());
+        assert_eq!(new_value["length"], 30);
+        assert_eq!(new_value["width"], 20);
+        // The record stays with whoever scouted it first
+        assert_eq!(new_value["scout_id"], "ada");
+
+        db.drop_test().await;
+    }
+}
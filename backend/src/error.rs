@@ -1,7 +1,9 @@
 use axum::http::StatusCode;
+use tracing::error;
 
 pub enum ApiError {
     SqlxError,
+    #[allow(dead_code)]
     ReqwestError,
 }
 
@@ -18,4 +20,10 @@ impl ApiError {
             ),
         }
     }
+
+    /// Logs a failed query and turns it into the error response, for `map_err` on sqlx results
+    pub fn sqlx(err: sqlx::Error) -> (StatusCode, String) {
+        error!("SQL query failed: {}", err);
+        ApiError::SqlxError.to_error(err.to_string())
+    }
 }
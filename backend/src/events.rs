@@ -0,0 +1,264 @@
+use std::{collections::VecDeque, convert::Infallible, sync::Mutex};
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderValue, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::stream::{self, Stream, StreamExt};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{error, info};
+
+use crate::{admin::check_admin_auth, model::AppState, webpush::PushStatus, ws::Assignment};
+
+// SseReturn cannot implement AsRef(str), as the serialized value would go out of scope.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SseReturn {
+    QueuedScout(String),
+    DeQueuedScout(String),
+    LoggedInScout(String),
+    ScoutConnected(String),
+    ScoutDisconnected(String),
+    ScoutAssigned(Assignment),
+    TeamMatchScouted {
+        scout_name: String,
+        team_key: String,
+        match_key: String,
+    },
+    CoverageGap {
+        match_key: String,
+        missing: Vec<String>,
+    },
+    BroadcastDelivery {
+        broadcast_id: String,
+        user_id: String,
+        scout_name: String,
+        socket: bool,
+        push: PushStatus,
+    },
+}
+
+impl SseReturn {
+    /// SSE event name, dashboards listen for these instead of the default `message`
+    pub fn name(&self) -> &'static str {
+        match self {
+            SseReturn::QueuedScout(_) => "queued_scout",
+            SseReturn::DeQueuedScout(_) => "dequeued_scout",
+            SseReturn::LoggedInScout(_) => "logged_in_scout",
+            SseReturn::ScoutConnected(_) => "scout_connected",
+            SseReturn::ScoutDisconnected(_) => "scout_disconnected",
+            SseReturn::ScoutAssigned(_) => "scout_assigned",
+            SseReturn::TeamMatchScouted { .. } => "team_match_scouted",
+            SseReturn::CoverageGap { .. } => "coverage_gap",
+            SseReturn::BroadcastDelivery { .. } => "broadcast_delivery",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BusEvent {
+    pub id: u64,
+    pub event: SseReturn,
+}
+
+impl BusEvent {
+    fn to_sse(&self) -> Event {
+        Event::default()
+            .id(self.id.to_string())
+            .event(self.event.name())
+            .data(serde_json::to_string(&self.event).expect("SseReturn struct not serializable"))
+    }
+}
+
+/// Events kept for dashboards that reconnect with `Last-Event-ID`
+const HISTORY_SIZE: usize = 512;
+
+/// Everything the admin dashboard is told about, every subscriber sees every event in order
+pub struct EventBus {
+    sender: broadcast::Sender<BusEvent>,
+    /// Recent events and the id the next one gets
+    history: Mutex<(VecDeque<BusEvent>, u64)>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(HISTORY_SIZE);
+        EventBus {
+            sender,
+            history: Mutex::new((VecDeque::with_capacity(HISTORY_SIZE), 1)),
+        }
+    }
+
+    pub fn publish(&self, event: SseReturn) {
+        // Sent while holding the lock so subscribers never see events out of order or twice
+        let mut history = self.history.lock().expect("Event history lock poisoned");
+        let (events, next_id) = &mut *history;
+
+        let event = BusEvent {
+            id: *next_id,
+            event,
+        };
+        *next_id += 1;
+
+        if events.len() == HISTORY_SIZE {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+
+        // Only fails when no dashboard is connected
+        let _ = self.sender.send(event);
+    }
+
+    /// Id of the latest event, 0 before anything was published
+    pub fn last_id(&self) -> u64 {
+        self.history.lock().expect("Event history lock poisoned").1 - 1
+    }
+
+    /// Events after `last_event_id` that are still in the history, and a receiver for everything after them
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> (Vec<BusEvent>, broadcast::Receiver<BusEvent>) {
+        let history = self.history.lock().expect("Event history lock poisoned");
+
+        let missed = match last_event_id {
+            Some(last_event_id) => history
+                .0
+                .iter()
+                .filter(|event| event.id > last_event_id)
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+
+        (missed, self.sender.subscribe())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    /// Comma separated event names, every event is sent when left out
+    types: Option<String>,
+    /// `last_event_id` from a queue snapshot, browsers can only send the header when reconnecting
+    last_event_id: Option<u64>,
+    /// Stands in for `x-access-token`, `EventSource` can't send headers
+    access_token: Option<String>,
+}
+
+pub async fn admin_sse_connect(
+    State(state): State<AppState>,
+    Query(query): Query<StreamQuery>,
+    mut headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    if let Some(access_token) = query.access_token {
+        if !headers.contains_key("x-access-token") {
+            let access_token = HeaderValue::from_str(&access_token)
+                .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid access token".to_string()))?;
+            headers.insert("x-access-token", access_token);
+        }
+    }
+    check_admin_auth(&state.db, headers.clone()).await?;
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok())
+        .or(query.last_event_id);
+    let types: Option<Vec<String>> = query.types.map(|types| {
+        types
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .collect()
+    });
+
+    info!(
+        "Admin connected to SSE stream, resuming after {:?}",
+        last_event_id
+    );
+
+    let (missed, receiver) = state.events.subscribe(last_event_id);
+
+    let live = BroadcastStream::new(receiver).filter_map(|event| async move {
+        match event {
+            Ok(event) => Some(event),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                error!("Admin SSE stream fell behind, skipped {} events", skipped);
+                None
+            }
+        }
+    });
+
+    let downstream = stream::iter(missed)
+        .chain(live)
+        .filter(move |event| {
+            let wanted = types
+                .as_ref()
+                .is_none_or(|types| types.iter().any(|name| name == event.event.name()));
+            async move { wanted }
+        })
+        .map(|event| Ok(event.to_sse()));
+
+    Ok(Sse::new(downstream).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(events: &[BusEvent]) -> Vec<u64> {
+        events.iter().map(|event| event.id).collect()
+    }
+
+    #[test]
+    fn replays_events_after_the_last_id() {
+        let bus = EventBus::new();
+        assert_eq!(bus.last_id(), 0);
+
+        for name in ["ada", "grace", "linus"] {
+            bus.publish(SseReturn::QueuedScout(name.to_string()));
+        }
+        assert_eq!(bus.last_id(), 3);
+
+        assert_eq!(ids(&bus.subscribe(Some(1)).0), vec![2, 3]);
+        assert_eq!(ids(&bus.subscribe(Some(3)).0), Vec::<u64>::new());
+        // A fresh dashboard starts from a snapshot instead
+        assert!(bus.subscribe(None).0.is_empty());
+    }
+
+    #[test]
+    fn live_events_follow_the_replay() {
+        let bus = EventBus::new();
+        bus.publish(SseReturn::ScoutConnected("ada".to_string()));
+
+        let (missed, mut receiver) = bus.subscribe(Some(0));
+        bus.publish(SseReturn::ScoutDisconnected("ada".to_string()));
+
+        assert_eq!(ids(&missed), vec![1]);
+        let live = receiver.try_recv().unwrap();
+        assert_eq!(live.id, 2);
+        assert_eq!(live.event.name(), "scout_disconnected");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn history_keeps_the_latest_events() {
+        let bus = EventBus::new();
+        for _ in 0..HISTORY_SIZE + 10 {
+            bus.publish(SseReturn::LoggedInScout("ada".to_string()));
+        }
+
+        let (missed, _) = bus.subscribe(Some(0));
+        assert_eq!(missed.len(), HISTORY_SIZE);
+        assert_eq!(missed[0].id, 11);
+        assert_eq!(missed.last().unwrap().id, bus.last_id());
+    }
+}
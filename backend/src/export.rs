@@ -0,0 +1,543 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::Response,
+};
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
+use http::HeaderMap;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    admin::{get_user_from_headers, resolve_event, EventQuery},
+    model::{AppState, Db},
+    season::{FieldKind, SeasonConfig, Seasons},
+};
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Dataset {
+    Matches,
+    Pits,
+    Teams,
+    Images,
+}
+
+impl FromStr for Dataset {
+    type Err = String;
+
+    fn from_str(dataset: &str) -> Result<Self, Self::Err> {
+        match dataset {
+            "matches" => Ok(Dataset::Matches),
+            "pits" => Ok(Dataset::Pits),
+            "teams" => Ok(Dataset::Teams),
+            "images" => Ok(Dataset::Images),
+            _ => Err(format!(
+                "Unknown dataset {dataset}, expected matches, pits, teams or images"
+            )),
+        }
+    }
+}
+
+impl Dataset {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dataset::Matches => "matches",
+            Dataset::Pits => "pits",
+            Dataset::Teams => "teams",
+            Dataset::Images => "images",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Opens directly in spreadsheets and Tableau
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!("Unknown format {format}, expected csv or json")),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+    pub event_key: String,
+    pub team_key: Option<String>,
+    /// Qualification match numbers, playoff matches are left out once either is set
+    pub from_match: Option<i32>,
+    pub to_match: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    event_key: Option<String>,
+    team_key: Option<String>,
+    from_match: Option<i32>,
+    to_match: Option<i32>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+const MATCH_COLUMNS: [&str; 8] = [
+    "id",
+    "match_key",
+    "team_key",
+    "event_key",
+    "scout_id",
+    "scout_name",
+    "source",
+    "created_at",
+];
+const PIT_COLUMNS: [&str; 16] = [
+    "id",
+    "team_key",
+    "event_key",
+    "status",
+    "width",
+    "length",
+    "is_short",
+    "is_camera",
+    "drivetrain",
+    "is_ground_intake",
+    "is_chute_intake",
+    "polish",
+    "scout_id",
+    "scout_name",
+    "source",
+    "created_at",
+];
+const IMAGE_COLUMNS: [&str; 10] = [
+    "name",
+    "team_key",
+    "event_key",
+    "url",
+    "scout_name",
+    "caption",
+    "tags",
+    "is_primary",
+    "near_duplicate_of",
+    "created_at",
+];
+
+/// Fields that average to a number, bools average to how often they were true
+fn is_numeric(kind: &FieldKind) -> bool {
+    matches!(
+        kind,
+        FieldKind::Count { .. } | FieldKind::Rating { .. } | FieldKind::Bool { .. }
+    )
+}
+
+/// Header row, match data is flattened into one column per form field, fields named like a match column are left out
+pub fn columns(dataset: Dataset, season: Option<&SeasonConfig>) -> Vec<String> {
+    let fields = season.map(|season| season.fields.as_slice()).unwrap_or(&[]);
+
+    match dataset {
+        Dataset::Matches => MATCH_COLUMNS
+            .iter()
+            .map(|column| column.to_string())
+            .chain(
+                fields
+                    .iter()
+                    .filter(|field| !MATCH_COLUMNS.contains(&field.name.as_str()))
+                    .map(|field| field.name.clone()),
+            )
+            .collect(),
+        Dataset::Pits => PIT_COLUMNS
+            .iter()
+            .map(|column| column.to_string())
+            .collect(),
+        Dataset::Teams => ["team_key", "matches", "avg_score"]
+            .iter()
+            .map(|column| column.to_string())
+            .chain(
+                fields
+                    .iter()
+                    .filter(|field| is_numeric(&field.kind))
+                    .map(|field| format!("avg_{}", field.name)),
+            )
+            .collect(),
+        Dataset::Images => IMAGE_COLUMNS
+            .iter()
+            .map(|column| column.to_string())
+            .collect(),
+    }
+}
+
+fn query(dataset: Dataset) -> &'static str {
+    match dataset {
+        Dataset::Matches | Dataset::Teams => "SELECT tm.data || (to_jsonb(tm) - 'data' - 'deleted_at') || jsonb_build_object('scout_name', u.name) FROM \"TeamMatches\" tm LEFT JOIN \"Users\" u ON u.id = tm.scout_id WHERE tm.event_key = $1 AND tm.deleted_at IS NULL AND ($2::TEXT IS NULL OR tm.team_key = $2) AND ($3::INT IS NULL OR (regexp_match(tm.match_key, '_qm([0-9]+)$'))[1]::INT >= $3) AND ($4::INT IS NULL OR (regexp_match(tm.match_key, '_qm([0-9]+)$'))[1]::INT <= $4) ORDER BY tm.created_at, tm.id",
+        Dataset::Pits => "SELECT to_jsonb(te) - 'drivetrain_enum' - 'deleted_at' || jsonb_build_object('drivetrain', te.drivetrain_enum, 'scout_name', u.name) FROM \"TeamEvents\" te LEFT JOIN \"Users\" u ON u.id = te.scout_id WHERE te.event_key = $1 AND te.deleted_at IS NULL AND ($2::TEXT IS NULL OR te.team_key = $2) ORDER BY te.team_key",
+        Dataset::Images => "SELECT to_jsonb(i) - 'storage_key' - 'content_hash' - 'phash' - 'scout_id' || jsonb_build_object('scout_name', u.name) FROM images i LEFT JOIN \"Users\" u ON u.id = i.scout_id WHERE i.event_key = $1 AND ($2::TEXT IS NULL OR i.team_key = $2) ORDER BY i.team_key, i.created_at",
+    }
+}
+
+/// Every row as a JSON object, read from the database as the stream is polled
+fn records(
+    db: Db,
+    dataset: Dataset,
+    filter: ExportFilter,
+) -> impl Stream<Item = Result<Map<String, Value>, sqlx::Error>> {
+    async_stream::try_stream! {
+        let mut rows = sqlx::query_scalar::<_, Value>(query(dataset))
+            .bind(&filter.event_key)
+            .bind(&filter.team_key);
+        // Only match scouting has match numbers to filter on
+        if matches!(dataset, Dataset::Matches | Dataset::Teams) {
+            rows = rows.bind(filter.from_match).bind(filter.to_match);
+        }
+        let mut rows = rows.fetch(&db.pool);
+
+        while let Some(row) = rows.try_next().await? {
+            if let Value::Object(row) = row {
+                yield row;
+            }
+        }
+    }
+}
+
+/// Running totals for one team, only one of these per team is kept while exporting
+#[derive(Default)]
+struct TeamTotals {
+    matches: i64,
+    score: f64,
+    fields: BTreeMap<String, f64>,
+}
+
+fn field_value(value: Option<&Value>) -> f64 {
+    match value {
+        Some(Value::Bool(true)) => 1.0,
+        Some(value) => value.as_f64().unwrap_or(0.0),
+        None => 0.0,
+    }
+}
+
+async fn team_records(
+    db: Db,
+    filter: ExportFilter,
+    season: Option<SeasonConfig>,
+) -> Result<Vec<Map<String, Value>>, sqlx::Error> {
+    let mut teams: BTreeMap<String, TeamTotals> = BTreeMap::new();
+    let fields: Vec<String> = season
+        .iter()
+        .flat_map(|season| season.fields.iter())
+        .filter(|field| is_numeric(&field.kind))
+        .map(|field| field.name.clone())
+        .collect();
+
+    let mut rows = Box::pin(records(db, Dataset::Matches, filter));
+    while let Some(row) = rows.try_next().await? {
+        let team_key = row
+            .get("team_key")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let totals = teams.entry(team_key).or_default();
+
+        totals.matches += 1;
+        totals.score += season.as_ref().map_or(0.0, |season| season.score(&row));
+        for field in fields.iter() {
+            *totals.fields.entry(field.clone()).or_default() += field_value(row.get(field));
+        }
+    }
+
+    Ok(teams
+        .into_iter()
+        .map(|(team_key, totals)| {
+            let matches = totals.matches as f64;
+            let mut record = Map::new();
+            record.insert("team_key".to_string(), Value::from(team_key));
+            record.insert("matches".to_string(), Value::from(totals.matches));
+            record.insert("avg_score".to_string(), Value::from(totals.score / matches));
+            for (field, total) in totals.fields {
+                record.insert(format!("avg_{field}"), Value::from(total / matches));
+            }
+            record
+        })
+        .collect())
+}
+
+fn csv_cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|value| csv_cell(Some(value)))
+            .collect::<Vec<String>>()
+            .join(";"),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn csv_line(cells: impl IntoIterator<Item = String>) -> Bytes {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer
+        .write_record(cells)
+        .expect("Writing CSV to memory can't fail");
+    Bytes::from(
+        writer
+            .into_inner()
+            .expect("Writing CSV to memory can't fail"),
+    )
+}
+
+/// The export as chunks of the encoded file, one per row
+pub fn export(
+    db: Db,
+    seasons: &Seasons,
+    dataset: Dataset,
+    filter: ExportFilter,
+    format: ExportFormat,
+) -> impl Stream<Item = Result<Bytes, sqlx::Error>> + Send + 'static {
+    let season = seasons.for_key(&filter.event_key).cloned();
+    let columns = columns(dataset, season.as_ref());
+
+    let rows = match dataset {
+        Dataset::Teams => stream::once(team_records(db, filter, season))
+            .map_ok(|teams| stream::iter(teams.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed(),
+        _ => records(db, dataset, filter).boxed(),
+    };
+
+    encode(rows, columns, format)
+}
+
+/// Frames rows as a CSV with a header or a JSON array
+fn encode(
+    rows: BoxStream<'static, Result<Map<String, Value>, sqlx::Error>>,
+    columns: Vec<String>,
+    format: ExportFormat,
+) -> BoxStream<'static, Result<Bytes, sqlx::Error>> {
+    match format {
+        ExportFormat::Csv => {
+            let header = csv_line(columns.clone());
+            stream::once(async move { Ok::<_, sqlx::Error>(header) })
+                .chain(rows.map_ok(move |row| {
+                    csv_line(columns.iter().map(|column| csv_cell(row.get(column))))
+                }))
+                .boxed()
+        }
+        ExportFormat::Json => {
+            let mut first = true;
+            stream::once(async { Ok::<_, sqlx::Error>(Bytes::from_static(b"[")) })
+                .chain(rows.map_ok(move |row| {
+                    let separator = if first { "" } else { "," };
+                    first = false;
+                    Bytes::from(format!("{separator}{}", Value::Object(row)))
+                }))
+                .chain(stream::once(async { Ok(Bytes::from_static(b"]")) }))
+                .boxed()
+        }
+    }
+}
+
+/// Streams a dataset as a file download, e.g. `/export/matches?event_key=2024orsal&format=csv`
+#[axum::debug_handler]
+pub async fn export_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(dataset): Path<Dataset>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    get_user_from_headers(&state.db, &headers).await?;
+    let event_key = resolve_event(
+        &state.db,
+        EventQuery {
+            event_key: query.event_key,
+        },
+    )
+    .await?;
+
+    // Event keys like 2024casj are all the filename takes, anything else could break out of the header
+    if event_key.is_empty()
+        || !event_key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid event key {event_key:?}"),
+        ));
+    }
+
+    let filename = format!(
+        "{}-{}.{}",
+        event_key,
+        dataset.name(),
+        query.format.extension()
+    );
+    let filter = ExportFilter {
+        event_key,
+        team_key: query.team_key,
+        from_match: query.from_match,
+        to_match: query.to_match,
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, query.format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        )
+        .body(Body::from_stream(export(
+            state.db.clone(),
+            &state.seasons,
+            dataset,
+            filter,
+            query.format,
+        )))
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn season() -> SeasonConfig {
+        serde_json::from_value(serde_json::json!({
+            "year": 2024,
+            "name": "Test",
+            "fields": [
+                { "name": "speaker", "phase": "tele", "type": "count", "min": 0, "max": 10 },
+                { "name": "stage", "phase": "endgame", "type": "choice", "options": ["park", "onstage"] },
+                { "name": "id", "phase": "post", "type": "text", "required": false }
+            ]
+        }))
+        .unwrap()
+    }
+
+    async fn encoded(rows: Vec<Map<String, Value>>, format: ExportFormat) -> String {
+        let columns = vec!["team_key".to_string(), "notes".to_string()];
+        let chunks: Vec<Bytes> = encode(
+            stream::iter(rows.into_iter().map(Ok)).boxed(),
+            columns,
+            format,
+        )
+        .try_collect()
+        .await
+        .unwrap();
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    fn row(team_key: &str, notes: Value) -> Map<String, Value> {
+        Map::from_iter([
+            ("team_key".to_string(), Value::from(team_key)),
+            ("notes".to_string(), notes),
+        ])
+    }
+
+    #[test]
+    fn columns_flatten_the_season_form() {
+        let season = season();
+
+        let matches = columns(Dataset::Matches, Some(&season));
+        assert_eq!(matches.len(), MATCH_COLUMNS.len() + 2);
+        assert_eq!(&matches[MATCH_COLUMNS.len()..], ["speaker", "stage"]);
+        assert_eq!(matches.iter().filter(|column| *column == "id").count(), 1);
+
+        assert_eq!(
+            columns(Dataset::Teams, Some(&season)),
+            ["team_key", "matches", "avg_score", "avg_speaker"]
+        );
+        assert_eq!(columns(Dataset::Matches, None), MATCH_COLUMNS);
+        assert_eq!(columns(Dataset::Pits, Some(&season)), PIT_COLUMNS);
+    }
+
+    #[tokio::test]
+    async fn csv_has_a_header_and_a_line_per_row() {
+        let rows = vec![
+            row("frc254", Value::from("fast, \"good\" intake")),
+            row("frc1540", Value::Null),
+        ];
+        assert_eq!(
+            encoded(rows, ExportFormat::Csv).await,
+            "team_key,notes\nfrc254,\"fast, \"\"good\"\" intake\"\nfrc1540,\n"
+        );
+        assert_eq!(encoded(vec![], ExportFormat::Csv).await, "team_key,notes\n");
+    }
+
+    #[tokio::test]
+    async fn json_is_one_array() {
+        let rows = vec![row("frc254", Value::from(1)), row("frc1540", Value::Null)];
+        let json: Value = serde_json::from_str(&encoded(rows, ExportFormat::Json).await).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        assert_eq!(json[0]["notes"], 1);
+        assert_eq!(encoded(vec![], ExportFormat::Json).await, "[]");
+    }
+
+    #[tokio::test]
+    async fn match_ranges_only_cover_quals() {
+        let Some(db) = Db::for_test().await else {
+            return;
+        };
+        sqlx::query("INSERT INTO \"Users\" (id, name, is_notify, is_admin, access_token) VALUES ('ada', 'Ada', FALSE, FALSE, 'a')")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO \"TeamMatches\" (match_key, team_key, event_key, season, data, scout_id) VALUES ('2024casj_qm1', 'frc254', '2024casj', 2024, '{\"id\": \"form\", \"speaker\": 3}', 'ada'), ('2024casj_qm12', 'frc254', '2024casj', 2024, '{}', 'ada'), ('2024casj_qf1m2', 'frc254', '2024casj', 2024, '{}', 'ada'), ('2024casj_sf2m1', 'frc254', '2024casj', 2024, '{}', 'ada')")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        let filter = ExportFilter {
+            event_key: "2024casj".to_string(),
+            from_match: Some(1),
+            to_match: Some(2),
+            ..Default::default()
+        };
+        let rows: Vec<Map<String, Value>> = records(db.clone(), Dataset::Matches, filter)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["match_key"], "2024casj_qm1");
+        assert_eq!(rows[0]["speaker"], 3);
+        // The form can't overwrite the real columns
+        assert!(rows[0]["id"].is_number());
+        assert_eq!(rows[0]["scout_name"], "Ada");
+
+        let everything = ExportFilter {
+            event_key: "2024casj".to_string(),
+            ..Default::default()
+        };
+        let rows: Vec<Map<String, Value>> = records(db.clone(), Dataset::Matches, everything)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 4);
+
+        db.drop_test().await;
+    }
+}
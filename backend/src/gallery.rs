@@ -0,0 +1,195 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use http::HeaderMap;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    admin::{get_user_from_headers, resolve_event, EventQuery},
+    error::ApiError,
+    model::{AppState, Image},
+    upload::stored_keys,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct GalleryQuery {
+    event_key: Option<String>,
+    team_key: Option<String>,
+    tag: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImageDetails {
+    caption: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+const MAX_CAPTION_LENGTH: usize = 500;
+
+/// Tags are free text from the pit scouting app, keep them consistent so filtering works
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .into_iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+async fn find_image(state: &AppState, name: &str) -> Result<Image, (StatusCode, String)> {
+    match sqlx::query_as::<_, Image>("SELECT * FROM images WHERE name = $1")
+        .bind(name)
+        .fetch_optional(&state.db.pool)
+        .await
+    {
+        Ok(Some(image)) => Ok(image),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("No image {name}"))),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+/// Photos for an event, newest first with each team's primary photo leading
+#[axum::debug_handler]
+pub async fn list_images(
+    State(state): State<AppState>,
+    Query(query): Query<GalleryQuery>,
+) -> Result<Json<Vec<Image>>, (StatusCode, String)> {
+    let event_key = resolve_event(
+        &state.db,
+        EventQuery {
+            event_key: query.event_key,
+        },
+    )
+    .await?;
+    let tag = query.tag.map(|tag| tag.trim().to_lowercase());
+
+    match sqlx::query_as::<_, Image>("SELECT * FROM images WHERE event_key = $1 AND ($2::TEXT IS NULL OR team_key = $2) AND ($3::TEXT IS NULL OR $3 = ANY(tags)) ORDER BY team_key, is_primary DESC, created_at DESC")
+        .bind(event_key)
+        .bind(query.team_key)
+        .bind(tag)
+        .fetch_all(&state.db.pool)
+        .await
+    {
+        Ok(images) => Ok(Json(images)),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn update_image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(details): Json<ImageDetails>,
+) -> Result<Json<Image>, (StatusCode, String)> {
+    get_user_from_headers(&state.db, &headers).await?;
+
+    if details
+        .caption
+        .as_ref()
+        .is_some_and(|caption| caption.len() > MAX_CAPTION_LENGTH)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Captions can be at most {MAX_CAPTION_LENGTH} characters"),
+        ));
+    }
+
+    // Fields left out of the request keep their current value
+    match sqlx::query_as::<_, Image>("UPDATE images SET caption = COALESCE($2, caption), tags = COALESCE($3, tags) WHERE name = $1 RETURNING *")
+        .bind(&name)
+        .bind(details.caption)
+        .bind(details.tags.map(normalize_tags))
+        .fetch_optional(&state.db.pool)
+        .await
+    {
+        Ok(Some(image)) => Ok(Json(image)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("No image {name}"))),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+/// Makes an image the one shown for its team, replacing any previous primary photo
+#[axum::debug_handler]
+pub async fn set_primary_image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<Json<Image>, (StatusCode, String)> {
+    get_user_from_headers(&state.db, &headers).await?;
+    let image = find_image(&state, &name).await?;
+
+    let mut tx = state.db.pool.begin().await.map_err(ApiError::sqlx)?;
+
+    sqlx::query("UPDATE images SET is_primary = FALSE WHERE event_key = $1 AND team_key = $2 AND is_primary")
+        .bind(&image.event_key)
+        .bind(&image.team_key)
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+    let image = sqlx::query_as::<_, Image>(
+        "UPDATE images SET is_primary = TRUE WHERE name = $1 RETURNING *",
+    )
+    .bind(&name)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(ApiError::sqlx)?;
+
+    tx.commit().await.map_err(ApiError::sqlx)?;
+
+    Ok(Json(image))
+}
+
+/// Removes an image and its stored files, only admins and the scout who took it can delete it
+#[axum::debug_handler]
+pub async fn delete_image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let user = get_user_from_headers(&state.db, &headers).await?;
+    let image = find_image(&state, &name).await?;
+
+    if !user.is_admin && user.id != image.scout_id {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only admins and the uploader can delete an image".to_string(),
+        ));
+    }
+
+    let mut tx = state.db.pool.begin().await.map_err(ApiError::sqlx)?;
+
+    sqlx::query("UPDATE images SET near_duplicate_of = NULL WHERE near_duplicate_of = $1")
+        .bind(&name)
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+    sqlx::query("DELETE FROM images WHERE name = $1")
+        .bind(&name)
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+    tx.commit().await.map_err(ApiError::sqlx)?;
+
+    // The row is gone so the image can't be served, a file left behind is only wasted space
+    for key in stored_keys(&image.name, &image.storage_key) {
+        if let Err(err) = state.image_store.delete(&key).await {
+            error!("Failed to delete stored image {}: {}", key, err);
+        }
+    }
+    if let Err(err) = state.image_cache.delete_dir(&image.name).await {
+        error!("Failed to purge cached resizes of {}: {}", image.name, err);
+    }
+
+    info!("{} deleted image {}", user.name, name);
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,872 @@
+use std::{collections::HashMap, str::FromStr};
+
+use axum::{
+    extract::{Multipart, Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{Postgres, Transaction};
+use tracing::info;
+
+use crate::{
+    admin::{get_current_event, get_user_from_headers},
+    error::ApiError,
+    export::{Dataset, ExportFormat},
+    model::{event_key_of, AppState, Db, TeamEvent},
+    season::{FieldKind, SeasonConfig, Seasons},
+};
+
+/// What to do with a record for a robot that already has one
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Leave the existing record and report the conflict
+    #[default]
+    Skip,
+    /// Delete the existing record, the deletion is kept in its revisions
+    Replace,
+    /// Add the record beside the existing one, pit data is merged into it like a live submission
+    Keep,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "replace" => Ok(ConflictPolicy::Replace),
+            "keep" => Ok(ConflictPolicy::Keep),
+            _ => Err(format!(
+                "Unknown conflict policy {policy}, expected skip, replace or keep"
+            )),
+        }
+    }
+}
+
+/// Our column names to the headers used in someone else's file, only mapped columns are read
+pub type Mapping = HashMap<String, String>;
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub source: String,
+    /// Records the import, and scouts imported records when the file's scout isn't one of ours
+    pub importer_id: String,
+    /// For pit rows without an event_key
+    pub event_key: Option<String>,
+    pub on_conflict: ConflictPolicy,
+    /// Checks everything and reports what would happen without saving
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportConflict {
+    pub row: usize,
+    pub team_key: String,
+    pub key: String,
+    pub existing_ids: Vec<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportError {
+    pub row: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub replaced: usize,
+    /// Already in the database with the same scout and data, e.g. when restoring a backup twice
+    pub unchanged: usize,
+    pub conflicts: Vec<ImportConflict>,
+    pub errors: Vec<ImportError>,
+    pub dry_run: bool,
+}
+
+/// Columns that describe the record rather than being part of the scouting form
+const MATCH_META: [&str; 9] = [
+    "id",
+    "match_key",
+    "team_key",
+    "event_key",
+    "season",
+    "scout_id",
+    "scout_name",
+    "source",
+    "created_at",
+];
+const PIT_META: [&str; 7] = [
+    "id",
+    "status",
+    "scout_id",
+    "scout_name",
+    "source",
+    "created_at",
+    "deleted_at",
+];
+
+fn apply_mapping(mut row: Map<String, Value>, mapping: Option<&Mapping>) -> Map<String, Value> {
+    let Some(mapping) = mapping else {
+        return row;
+    };
+
+    mapping
+        .iter()
+        .filter_map(|(ours, theirs)| Some((ours.clone(), row.remove(theirs)?)))
+        .collect()
+}
+
+/// Rows from an export of ours, or from another team's file with a mapping
+pub fn parse(
+    data: &[u8],
+    format: ExportFormat,
+    mapping: Option<&Mapping>,
+) -> Result<Vec<Map<String, Value>>, String> {
+    let rows: Vec<Map<String, Value>> = match format {
+        ExportFormat::Json => {
+            serde_json::from_slice(data).map_err(|err| format!("Invalid JSON import: {err}"))?
+        }
+        ExportFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(data);
+            let headers = reader
+                .headers()
+                .map_err(|err| format!("Invalid CSV header: {err}"))?
+                .clone();
+
+            let mut rows = vec![];
+            for record in reader.records() {
+                let record = record.map_err(|err| format!("Invalid CSV: {err}"))?;
+                rows.push(
+                    headers
+                        .iter()
+                        .zip(record.iter())
+                        .map(|(header, cell)| (header.trim().to_string(), Value::from(cell)))
+                        .collect(),
+                );
+            }
+            rows
+        }
+    };
+
+    Ok(rows
+        .into_iter()
+        .map(|row| apply_mapping(row, mapping))
+        .collect())
+}
+
+/// CSV cells are all text, turns them back into the type the form field expects
+fn coerce(value: Value, kind: Option<&FieldKind>) -> Value {
+    let Value::String(text) = value else {
+        return value;
+    };
+    let text = text.trim();
+
+    match kind {
+        _ if text.is_empty() => Value::Null,
+        Some(FieldKind::Choice { .. } | FieldKind::Text { .. }) => Value::from(text),
+        _ => {
+            if let Ok(number) = text.parse::<i64>() {
+                Value::from(number)
+            } else if let Some(flag) = parse_bool(text) {
+                Value::Bool(flag)
+            } else {
+                Value::from(text)
+            }
+        }
+    }
+}
+
+fn parse_bool(text: &str) -> Option<bool> {
+    match text.to_lowercase().as_str() {
+        "true" | "yes" | "y" => Some(true),
+        "false" | "no" | "n" => Some(false),
+        _ => None,
+    }
+}
+
+/// Other teams' spreadsheets often leave off the `frc` prefix
+fn normalize_team_key(team_key: &str) -> String {
+    let team_key = team_key.trim().to_lowercase();
+    match team_key.parse::<u32>() {
+        Ok(number) => format!("frc{number}"),
+        Err(_) => team_key,
+    }
+}
+
+fn take_string(row: &mut Map<String, Value>, key: &str) -> Option<String> {
+    match row.remove(key)? {
+        Value::String(text) if text.trim().is_empty() => None,
+        Value::String(text) => Some(text.trim().to_string()),
+        Value::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+fn created_at(row: &mut Map<String, Value>) -> Result<Option<DateTime<Utc>>, String> {
+    take_string(row, "created_at")
+        .map(|created_at| {
+            created_at
+                .parse()
+                .map_err(|err| format!("Invalid created_at {created_at}: {err}"))
+        })
+        .transpose()
+}
+
+/// A row's scout as written in the file, `Users.id` and name
+type ScoutKey = (Option<String>, Option<String>);
+
+/// Our scout with the id in the file, else the only one with its name, else whoever is importing
+async fn scout_id(
+    tx: &mut Transaction<'_, Postgres>,
+    scouts: &mut HashMap<ScoutKey, Option<String>>,
+    scout: ScoutKey,
+    options: &ImportOptions,
+) -> Result<String, sqlx::Error> {
+    let id = match scouts.get(&scout) {
+        Some(id) => id.clone(),
+        None => {
+            // Names aren't unique, a name shared by several scouts can't say which one it was
+            let id = sqlx::query_scalar::<_, Option<String>>("SELECT COALESCE((SELECT id FROM \"Users\" WHERE id = $1), (SELECT min(id) FROM \"Users\" WHERE name = $2 HAVING count(*) = 1))")
+                .bind(&scout.0)
+                .bind(&scout.1)
+                .fetch_one(&mut **tx)
+                .await?;
+            scouts.insert(scout, id.clone());
+            id
+        }
+    };
+
+    Ok(id.unwrap_or_else(|| options.importer_id.clone()))
+}
+
+/// Soft deletes replaced records, keeping them in their revisions like an admin delete
+async fn replace_records(
+    tx: &mut Transaction<'_, Postgres>,
+    table: &str,
+    ids: &[i64],
+    options: &ImportOptions,
+) -> Result<(), sqlx::Error> {
+    // TeamMatches ids are INT4 and TeamEvents ids INT8, both are read back as INT8
+    let query = format!("UPDATE \"{table}\" t SET deleted_at = now() WHERE t.id = ANY($1) RETURNING t.id::BIGINT, to_jsonb(t)");
+    let deleted = sqlx::query_as::<_, (i64, Value)>(&query)
+        .bind(ids)
+        .fetch_all(&mut **tx)
+        .await?;
+
+    for (id, new_value) in deleted {
+        let mut old_value = new_value.clone();
+        if let Some(old_value) = old_value.as_object_mut() {
+            old_value.insert("deleted_at".to_string(), Value::Null);
+        }
+
+        sqlx::query("INSERT INTO \"Revisions\" (table_name, record_id, action, editor_id, old_value, new_value) VALUES ($1, $2, 'delete', $3, $4, $5)")
+            .bind(table)
+            .bind(id)
+            .bind(&options.importer_id)
+            .bind(old_value)
+            .bind(new_value)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+enum RowOutcome {
+    Imported,
+    Replaced,
+    Unchanged,
+    Conflict(ImportConflict),
+    Invalid(String),
+}
+
+async fn import_team_match(
+    tx: &mut Transaction<'_, Postgres>,
+    seasons: &Seasons,
+    scouts: &mut HashMap<ScoutKey, Option<String>>,
+    row_number: usize,
+    mut row: Map<String, Value>,
+    options: &ImportOptions,
+) -> Result<RowOutcome, sqlx::Error> {
+    let Some(match_key) = take_string(&mut row, "match_key") else {
+        return Ok(RowOutcome::Invalid("Missing match_key".to_string()));
+    };
+    let Some(team_key) = take_string(&mut row, "team_key").map(|key| normalize_team_key(&key))
+    else {
+        return Ok(RowOutcome::Invalid("Missing team_key".to_string()));
+    };
+    let scout = (
+        take_string(&mut row, "scout_id"),
+        take_string(&mut row, "scout_name"),
+    );
+    let created_at = match created_at(&mut row) {
+        Ok(created_at) => created_at,
+        Err(err) => return Ok(RowOutcome::Invalid(err)),
+    };
+    for column in MATCH_META {
+        row.remove(column);
+    }
+
+    let season: &SeasonConfig = match seasons.for_key(&match_key) {
+        Some(season) => season,
+        None => {
+            return Ok(RowOutcome::Invalid(format!(
+                "No season config for match {match_key}"
+            )))
+        }
+    };
+    let row: Map<String, Value> = row
+        .into_iter()
+        .map(|(name, value)| {
+            let kind = season.field(&name).map(|field| &field.kind);
+            (name, coerce(value, kind))
+        })
+        .collect();
+    // The same checks as a live submission
+    let data = match season.validate(&Value::Object(row)) {
+        Ok(data) => Value::from(data),
+        Err(err) => return Ok(RowOutcome::Invalid(err)),
+    };
+
+    let scout_id = scout_id(tx, scouts, scout, options).await?;
+
+    let existing = sqlx::query_as::<_, (i64, String, Value)>("SELECT id::BIGINT, scout_id, data FROM \"TeamMatches\" WHERE match_key = $1 AND team_key = $2 AND deleted_at IS NULL FOR UPDATE")
+        .bind(&match_key)
+        .bind(&team_key)
+        .fetch_all(&mut **tx)
+        .await?;
+
+    if existing.iter().any(|(_, existing_scout, existing_data)| {
+        *existing_scout == scout_id && *existing_data == data
+    }) {
+        return Ok(RowOutcome::Unchanged);
+    }
+
+    let existing_ids: Vec<i64> = existing.iter().map(|(id, _, _)| *id).collect();
+    let replaced = !existing_ids.is_empty();
+    match options.on_conflict {
+        ConflictPolicy::Skip if replaced => {
+            return Ok(RowOutcome::Conflict(ImportConflict {
+                row: row_number,
+                team_key,
+                key: match_key,
+                existing_ids,
+            }))
+        }
+        ConflictPolicy::Replace if replaced => {
+            replace_records(tx, "TeamMatches", &existing_ids, options).await?
+        }
+        _ => {}
+    }
+
+    let event_key = event_key_of(&match_key).to_string();
+    sqlx::query("INSERT INTO \"TeamMatches\" (match_key, team_key, event_key, season, data, scout_id, source, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, COALESCE($8, now()))")
+        .bind(&match_key)
+        .bind(&team_key)
+        .bind(event_key)
+        .bind(season.year)
+        .bind(data)
+        .bind(scout_id)
+        .bind(&options.source)
+        .bind(created_at)
+        .execute(&mut **tx)
+        .await?;
+
+    match (options.on_conflict, replaced) {
+        (ConflictPolicy::Replace, true) => Ok(RowOutcome::Replaced),
+        _ => Ok(RowOutcome::Imported),
+    }
+}
+
+async fn import_pit_data(
+    tx: &mut Transaction<'_, Postgres>,
+    scouts: &mut HashMap<ScoutKey, Option<String>>,
+    row_number: usize,
+    mut row: Map<String, Value>,
+    options: &ImportOptions,
+) -> Result<RowOutcome, sqlx::Error> {
+    let Some(team_key) = take_string(&mut row, "team_key").map(|key| normalize_team_key(&key))
+    else {
+        return Ok(RowOutcome::Invalid("Missing team_key".to_string()));
+    };
+    let Some(event_key) = take_string(&mut row, "event_key").or(options.event_key.clone()) else {
+        return Ok(RowOutcome::Invalid("Missing event_key".to_string()));
+    };
+    let scout = (
+        take_string(&mut row, "scout_id"),
+        take_string(&mut row, "scout_name"),
+    );
+    let created_at = match created_at(&mut row) {
+        Ok(created_at) => created_at,
+        Err(err) => return Ok(RowOutcome::Invalid(err)),
+    };
+    for column in PIT_META {
+        row.remove(column);
+    }
+
+    // Keys are text even when they look like numbers
+    let mut row: Map<String, Value> = row
+        .into_iter()
+        .map(|(name, value)| (name, coerce(value, None)))
+        .collect();
+    row.insert("team_key".to_string(), Value::from(team_key));
+    row.insert("event_key".to_string(), Value::from(event_key));
+    row.insert(
+        "scout_id".to_string(),
+        Value::from(scout_id(tx, scouts, scout, options).await?),
+    );
+
+    // The same checks as a live submission
+    let form: TeamEvent = match serde_json::from_value(Value::Object(row)) {
+        Ok(form) => form,
+        Err(err) => return Ok(RowOutcome::Invalid(format!("Invalid pit data: {err}"))),
+    };
+
+    let existing_ids = sqlx::query_scalar::<_, i64>("SELECT id FROM \"TeamEvents\" WHERE team_key = $1 AND event_key = $2 AND deleted_at IS NULL FOR UPDATE")
+        .bind(&form.team_key)
+        .bind(&form.event_key)
+        .fetch_all(&mut **tx)
+        .await?;
+
+    let replaced = !existing_ids.is_empty();
+    match options.on_conflict {
+        ConflictPolicy::Skip if replaced => {
+            return Ok(RowOutcome::Conflict(ImportConflict {
+                row: row_number,
+                team_key: form.team_key,
+                key: form.event_key,
+                existing_ids,
+            }))
+        }
+        ConflictPolicy::Replace if replaced => {
+            replace_records(tx, "TeamEvents", &existing_ids, options).await?
+        }
+        _ => {}
+    }
+
+    sqlx::query("INSERT INTO \"TeamEvents\" AS te (team_key, event_key, width, length, is_short, is_camera, drivetrain_enum, is_ground_intake, is_chute_intake, polish, scout_id, source, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, COALESCE($13, now())) ON CONFLICT (team_key, event_key) WHERE deleted_at IS NULL DO UPDATE SET width = COALESCE(EXCLUDED.width, te.width), length = COALESCE(EXCLUDED.length, te.length), is_short = COALESCE(EXCLUDED.is_short, te.is_short), is_camera = COALESCE(EXCLUDED.is_camera, te.is_camera), drivetrain_enum = COALESCE(EXCLUDED.drivetrain_enum, te.drivetrain_enum), is_ground_intake = COALESCE(EXCLUDED.is_ground_intake, te.is_ground_intake), is_chute_intake = COALESCE(EXCLUDED.is_chute_intake, te.is_chute_intake), polish = COALESCE(EXCLUDED.polish, te.polish)")
+        .bind(form.team_key)
+        .bind(form.event_key)
+        .bind(form.width)
+        .bind(form.length)
+        .bind(form.is_short)
+        .bind(form.is_camera)
+        .bind(form.drivetrain)
+        .bind(form.is_ground_intake)
+        .bind(form.is_chute_intake)
+        .bind(form.polish)
+        .bind(form.scout_id)
+        .bind(&options.source)
+        .bind(created_at)
+        .execute(&mut **tx)
+        .await?;
+
+    match (options.on_conflict, replaced) {
+        (ConflictPolicy::Replace, true) => Ok(RowOutcome::Replaced),
+        _ => Ok(RowOutcome::Imported),
+    }
+}
+
+/// Imports every valid row in one transaction, rows that fail validation or conflict are reported and left out
+pub async fn import_rows(
+    db: &Db,
+    seasons: &Seasons,
+    dataset: Dataset,
+    rows: Vec<Map<String, Value>>,
+    options: &ImportOptions,
+) -> Result<ImportReport, sqlx::Error> {
+    let mut report = ImportReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+    let mut scouts: HashMap<ScoutKey, Option<String>> = HashMap::new();
+
+    let mut tx = db.pool.begin().await?;
+
+    // Numbered from 1 like the data rows of a spreadsheet
+    for (row_number, row) in (1..).zip(rows) {
+        let outcome = match dataset {
+            Dataset::Matches => {
+                import_team_match(&mut tx, seasons, &mut scouts, row_number, row, options).await?
+            }
+            Dataset::Pits => {
+                import_pit_data(&mut tx, &mut scouts, row_number, row, options).await?
+            }
+            Dataset::Teams | Dataset::Images => {
+                RowOutcome::Invalid(format!("{} can't be imported", dataset.name()))
+            }
+        };
+
+        match outcome {
+            RowOutcome::Imported => report.imported += 1,
+            RowOutcome::Replaced => report.replaced += 1,
+            RowOutcome::Unchanged => report.unchanged += 1,
+            RowOutcome::Conflict(conflict) => report.conflicts.push(conflict),
+            RowOutcome::Invalid(error) => report.errors.push(ImportError {
+                row: row_number,
+                error,
+            }),
+        }
+    }
+
+    if options.dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+
+    info!(
+        "Imported {} {} from {}, replaced {}, {} conflicts, {} errors",
+        report.imported,
+        dataset.name(),
+        options.source,
+        report.replaced,
+        report.conflicts.len(),
+        report.errors.len()
+    );
+
+    Ok(report)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    source: String,
+    event_key: Option<String>,
+    #[serde(default)]
+    format: ExportFormat,
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Takes a `file` field, and a `mapping` field for files that don't use our column names
+#[axum::debug_handler]
+pub async fn import_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(dataset): Path<Dataset>,
+    Query(query): Query<ImportQuery>,
+    mut multipart: Multipart,
+) -> Result<Json<ImportReport>, (StatusCode, String)> {
+    let user = get_user_from_headers(&state.db, &headers).await?;
+    if !user.is_admin {
+        return Err((StatusCode::UNAUTHORIZED, "User is not admin".to_string()));
+    }
+
+    let mut file = None;
+    let mut mapping: Option<Mapping> = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return Err((StatusCode::BAD_REQUEST, err.to_string())),
+        };
+
+        let name = field.name().unwrap_or_default().to_string();
+        let bytes = match field.bytes().await {
+            Ok(bytes) => bytes,
+            Err(err) => return Err((StatusCode::BAD_REQUEST, err.to_string())),
+        };
+
+        match name.as_str() {
+            "file" => file = Some(bytes),
+            "mapping" => {
+                mapping = match serde_json::from_slice(&bytes) {
+                    Ok(mapping) => Some(mapping),
+                    Err(err) => {
+                        return Err((StatusCode::BAD_REQUEST, format!("Invalid mapping: {err}")))
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let Some(file) = file else {
+        return Err((StatusCode::BAD_REQUEST, "Missing file field".to_string()));
+    };
+
+    let rows = parse(&file, query.format, mapping.as_ref())
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    // A mistyped event_key is an error, the current event is only a fallback for pit rows without one
+    let event_key = match query.event_key {
+        Some(event_key) => {
+            match sqlx::query("SELECT 1 FROM \"Events\" WHERE event_key = $1")
+                .bind(&event_key)
+                .fetch_optional(&state.db.pool)
+                .await
+            {
+                Ok(Some(_)) => Some(event_key),
+                Ok(None) => return Err((StatusCode::BAD_REQUEST, format!("No event {event_key}"))),
+                Err(err) => return Err(ApiError::sqlx(err)),
+            }
+        }
+        None => get_current_event(&state.db).await.ok(),
+    };
+
+    let options = ImportOptions {
+        source: query.source,
+        importer_id: user.id,
+        event_key,
+        on_conflict: query.on_conflict,
+        dry_run: query.dry_run,
+    };
+
+    match import_rows(&state.db, &state.seasons, dataset, rows, &options).await {
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_csv_through_a_mapping() {
+        let csv = b"Team,Match,Scout,Ignored\n254,2024casj_qm1,Ada,x\n";
+        let mapping = Mapping::from([
+            ("team_key".to_string(), "Team".to_string()),
+            ("match_key".to_string(), "Match".to_string()),
+        ]);
+
+        let rows = parse(csv, ExportFormat::Csv, Some(&mapping)).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].len(), 2);
+        assert_eq!(rows[0]["team_key"], "254");
+        assert_eq!(rows[0]["match_key"], "2024casj_qm1");
+    }
+
+    #[test]
+    fn parses_our_own_json_export() {
+        let json = br#"[{"team_key": "frc254", "auto_amp_succeed": 3}]"#;
+
+        let rows = parse(json, ExportFormat::Json, None).unwrap();
+        assert_eq!(rows[0]["auto_amp_succeed"], 3);
+        assert!(parse(b"{", ExportFormat::Json, None).is_err());
+    }
+
+    #[test]
+    fn coerces_csv_cells_to_field_types() {
+        let text = FieldKind::Text { max_length: None };
+
+        assert_eq!(coerce(Value::from(" 12 "), None), Value::from(12));
+        assert_eq!(coerce(Value::from("Yes"), None), Value::Bool(true));
+        assert_eq!(coerce(Value::from("n"), None), Value::Bool(false));
+        assert_eq!(coerce(Value::from(""), None), Value::Null);
+        assert_eq!(coerce(Value::from("12"), Some(&text)), Value::from("12"));
+        assert_eq!(coerce(Value::from(3), None), Value::from(3));
+    }
+
+    #[test]
+    fn normalizes_team_keys() {
+        assert_eq!(normalize_team_key("254"), "frc254");
+        assert_eq!(normalize_team_key(" FRC254 "), "frc254");
+        assert_eq!(normalize_team_key("0254"), "frc254");
+    }
+
+    #[test]
+    fn parses_conflict_policies() {
+        assert_eq!("skip".parse(), Ok(ConflictPolicy::Skip));
+        assert_eq!("replace".parse(), Ok(ConflictPolicy::Replace));
+        assert_eq!("keep".parse(), Ok(ConflictPolicy::Keep));
+        assert!("merge".parse::<ConflictPolicy>().is_err());
+    }
+
+    fn options(on_conflict: ConflictPolicy) -> ImportOptions {
+        ImportOptions {
+            source: "backup".to_string(),
+            importer_id: "admin".to_string(),
+            event_key: None,
+            on_conflict,
+            dry_run: false,
+        }
+    }
+
+    fn match_row(seasons: &Seasons, auto_amp_succeed: i64) -> Map<String, Value> {
+        let mut row = seasons.get(2024).unwrap().default_data();
+        row.insert("match_key".to_string(), Value::from("2024casj_qm1"));
+        row.insert("team_key".to_string(), Value::from("254"));
+        row.insert(
+            "auto_amp_succeed".to_string(),
+            Value::from(auto_amp_succeed),
+        );
+        row
+    }
+
+    async fn live_matches(db: &Db) -> Vec<(i64, Value)> {
+        sqlx::query_as(
+            "SELECT id::BIGINT, data FROM \"TeamMatches\" WHERE deleted_at IS NULL ORDER BY id",
+        )
+        .fetch_all(&db.pool)
+        .await
+        .unwrap()
+    }
+
+    /// A database with the importer and one scout, None when no test database is configured
+    async fn setup() -> Option<(Db, Seasons)> {
+        let db = Db::for_test().await?;
+        sqlx::query("INSERT INTO \"Users\" (id, name, is_notify, is_admin, access_token) VALUES ('admin', 'Admin', FALSE, TRUE, 'a'), ('ada', 'Ada', FALSE, FALSE, 'b')")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        Some((db, Seasons::load("seasons").unwrap()))
+    }
+
+    #[tokio::test]
+    async fn importing_over_an_existing_match() {
+        let Some((db, seasons)) = setup().await else {
+            return;
+        };
+        let import = |row, policy| {
+            let (db, seasons) = (&db, &seasons);
+            async move {
+                import_rows(db, seasons, Dataset::Matches, vec![row], &options(policy))
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let report = import(match_row(&seasons, 1), ConflictPolicy::Skip).await;
+        assert_eq!(report.imported, 1);
+        let original = live_matches(&db).await[0].0;
+
+        // Restoring the same backup twice changes nothing
+        let report = import(match_row(&seasons, 1), ConflictPolicy::Skip).await;
+        assert_eq!(report.unchanged, 1);
+
+        let report = import(match_row(&seasons, 2), ConflictPolicy::Skip).await;
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].existing_ids, vec![original]);
+
+        let report = import(match_row(&seasons, 2), ConflictPolicy::Replace).await;
+        assert_eq!(report.replaced, 1);
+        let live = live_matches(&db).await;
+        assert_eq!(live.len(), 1);
+        assert_ne!(live[0].0, original);
+        assert_eq!(live[0].1["auto_amp_succeed"], 2);
+
+        let revisions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM \"Revisions\" WHERE table_name = 'TeamMatches' AND record_id = $1 AND action = 'delete'")
+            .bind(original)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(revisions, 1);
+
+        let report = import(match_row(&seasons, 3), ConflictPolicy::Keep).await;
+        assert_eq!(report.imported, 1);
+        assert_eq!(live_matches(&db).await.len(), 2);
+
+        db.drop_test().await;
+    }
+
+    #[tokio::test]
+    async fn importing_over_existing_pit_data() {
+        let Some((db, seasons)) = setup().await else {
+            return;
+        };
+        let pit_row = |width: i64| {
+            Map::from_iter([
+                ("team_key".to_string(), Value::from("frc254")),
+                ("event_key".to_string(), Value::from("2024casj")),
+                ("width".to_string(), Value::from(width)),
+                ("scout_name".to_string(), Value::from("Ada")),
+            ])
+        };
+        let import = |row, policy| {
+            let (db, seasons) = (&db, &seasons);
+            async move {
+                import_rows(db, seasons, Dataset::Pits, vec![row], &options(policy))
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(import(pit_row(20), ConflictPolicy::Skip).await.imported, 1);
+        assert_eq!(
+            import(pit_row(24), ConflictPolicy::Skip)
+                .await
+                .conflicts
+                .len(),
+            1
+        );
+        assert_eq!(
+            import(pit_row(24), ConflictPolicy::Replace).await.replaced,
+            1
+        );
+
+        let live: Vec<(i16, String)> =
+            sqlx::query_as("SELECT width, scout_id FROM \"TeamEvents\" WHERE deleted_at IS NULL")
+                .fetch_all(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(live, vec![(24, "ada".to_string())]);
+
+        db.drop_test().await;
+    }
+
+    #[tokio::test]
+    async fn imported_rows_are_credited_by_scout_id() {
+        let Some((db, seasons)) = setup().await else {
+            return;
+        };
+        // A second Ada makes the name ambiguous
+        sqlx::query("INSERT INTO \"Users\" (id, name, is_notify, is_admin, access_token) VALUES ('ada2', 'Ada', FALSE, FALSE, 'c'), ('grace', 'Grace', FALSE, FALSE, 'd')")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let row = |match_key: &str, scout: &[(&str, &str)]| {
+            let mut row = match_row(&seasons, 1);
+            row.insert("match_key".to_string(), Value::from(match_key));
+            for (column, value) in scout {
+                row.insert(column.to_string(), Value::from(*value));
+            }
+            row
+        };
+        let rows = vec![
+            row(
+                "2024casj_qm1",
+                &[("scout_id", "ada2"), ("scout_name", "Ada")],
+            ),
+            row("2024casj_qm2", &[("scout_name", "Ada")]),
+            row(
+                "2024casj_qm3",
+                &[("scout_id", "someone"), ("scout_name", "Grace")],
+            ),
+        ];
+
+        let report = import_rows(
+            &db,
+            &seasons,
+            Dataset::Matches,
+            rows,
+            &options(ConflictPolicy::Skip),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.imported, 3);
+
+        let scouts: Vec<(String, String)> =
+            sqlx::query_as("SELECT match_key, scout_id FROM \"TeamMatches\" ORDER BY match_key")
+                .fetch_all(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            scouts,
+            vec![
+                ("2024casj_qm1".to_string(), "ada2".to_string()),
+                // Either Ada could have scouted it, so the importer is credited
+                ("2024casj_qm2".to_string(), "admin".to_string()),
+                // Another instance's id falls back to the name
+                ("2024casj_qm3".to_string(), "grace".to_string()),
+            ]
+        );
+
+        db.drop_test().await;
+    }
+}
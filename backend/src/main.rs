@@ -1,10 +1,10 @@
 use axum::{
-    extract::{DefaultBodyLimit, Host},
+    extract::{DefaultBodyLimit, Host, State},
     handler::HandlerWithoutStateExt,
     http::{StatusCode, Uri},
-    response::{sse::Event, IntoResponse, Redirect},
-    routing::{get, post},
-    BoxError, Json, Router,
+    response::{IntoResponse, Redirect},
+    routing::{delete, get, patch, post, put},
+    BoxError, Extension, Json, Router,
 };
 
 use axum_server::tls_rustls::RustlsConfig;
@@ -20,12 +20,23 @@ use tower_http::cors::CorsLayer;
 use tracing::{error, info};
 use tracing_subscriber::FmtSubscriber;
 
-use self::model::TeamMatch;
-
 mod admin;
 mod auth;
+mod broadcast;
+mod cli;
+mod coverage;
+mod dashboard;
+mod edit;
 mod error;
+mod events;
+mod export;
+mod gallery;
+mod import;
 mod model;
+mod season;
+mod shifts;
+mod stats;
+mod storage;
 mod submit;
 mod upload;
 mod webpush;
@@ -40,6 +51,10 @@ struct Ports {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(command) = std::env::args().nth(1) {
+        return cli::run(&command, std::env::args().skip(2).collect()).await;
+    }
+
     dotenv().ok();
     let mode = std::env::var("MODE").expect("MODE not set");
     let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
@@ -49,14 +64,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let ctx = ReqwestClient::new();
 
-    let (tx, _rx) = tokio::sync::watch::channel(Ok(Event::default())); // tx is the upstream, while rx is the downstream
+    let seasons_dir = std::env::var("SEASONS_DIR").unwrap_or("seasons".to_string());
+    let seasons = season::Seasons::load(&seasons_dir).expect("Failed to load season configs");
+
+    let image_store = storage::from_env().expect("Failed to set up image storage");
+    let image_cache_dir = std::env::var("IMAGE_CACHE_DIR").unwrap_or("image_cache".to_string());
+
+    let push = webpush::PushService::start(db.clone());
 
     let state = model::AppState {
         db, // Database
         ctx,
-        sse_upstream: Arc::new(Mutex::new(tx)),
+        events: Arc::new(events::EventBus::new()),
         queue_manager: Arc::new(Mutex::new(ws::QueueManager::new())),
+        seasons: Arc::new(seasons),
+        image_store,
+        image_cache: Arc::new(storage::LocalStore::new(image_cache_dir)),
+        image_workers: upload::ImageWorkers::from_env(),
+        push,
     };
+    if let Err(err) = state.push.flag_stale_subscriptions().await {
+        error!(
+            "Failed to check push subscriptions for old VAPID keys: {}",
+            err
+        );
+    }
+
+    tokio::spawn(shifts::shift_reminders(
+        state.db.clone(),
+        state.push.clone(),
+    ));
+
     let router = init_router(state);
 
     if mode == "PROD" {
@@ -102,9 +140,9 @@ async fn prod_server(app: Router) -> Result<(), Box<dyn std::error::Error>> {
 }
 
 fn init_router(state: model::AppState) -> Router {
-    let (layer, io) = SocketIo::new_layer();
+    let (layer, io) = SocketIo::builder().with_state(state.clone()).build_layer();
 
-    io.ns("/socket.io", ws::on_connect);
+    ws::register(&io);
 
     let max_image_size: usize = std::env::var("MAX_IMAGE_SIZE")
         .expect("MAX_IMAGE_SIZE not set")
@@ -112,6 +150,13 @@ fn init_router(state: model::AppState) -> Router {
         .unwrap_or(50)
         * 1024
         * 1024;
+    // Exports restored from a backup can be far bigger than a photo
+    let max_import_size: usize = std::env::var("MAX_IMPORT_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(50)
+        * 1024
+        * 1024;
     // post for json data, any request involving sending an access code should be such
     Router::new()
         .route("/health", get(health))
@@ -119,19 +164,68 @@ fn init_router(state: model::AppState) -> Router {
         .route("/auth/check", post(auth::check_auth))
         .route("/submit/image/:image", get(upload::image))
         .route("/submit/upload", post(upload::upload))
+        .route("/submit/pit", post(submit::submit_pit_handler))
         .layer(DefaultBodyLimit::max(max_image_size))
         .route("/auth/slack", get(auth::slack_callback))
+        .route(
+            "/submit/match/:id",
+            put(edit::update_team_match).delete(edit::delete_team_match),
+        )
+        .route(
+            "/submit/match/:id/revisions",
+            get(edit::get_team_match_revisions),
+        )
+        .route(
+            "/submit/pit/:id",
+            put(edit::update_pit_data).delete(edit::delete_pit_data),
+        )
+        .route(
+            "/submit/pit/:id/revisions",
+            get(edit::get_pit_data_revisions),
+        )
         .route(
             "/admin/users/setPermissions",
             post(admin::set_user_permissions),
         )
-        .route("/admin/sse/get/stream", get(submit::admin_sse_connect))
+        .route("/admin/sse/get/stream", get(events::admin_sse_connect))
+        .route("/admin/queue/snapshot", get(admin::get_queue_snapshot))
+        .route("/admin/dashboard", get(dashboard::get_dashboard))
+        .route("/admin/coverage", get(coverage::get_coverage))
+        .route("/export/:dataset", get(export::export_handler))
+        .route(
+            "/admin/import/:dataset",
+            post(import::import_handler).layer(DefaultBodyLimit::max(max_import_size)),
+        )
         .route("/match/get/current", get(admin::get_current_match))
-        .route("/admin/users/get/all", get(admin::get_scouts_and_scouted))
+        .route("/match/get/finished", get(admin::get_finished_matches))
+        .route("/events", get(admin::get_events))
+        .route("/events/current", get(admin::get_current_event_handler))
+        .route("/admin/newEvent", post(admin::new_event))
+        .route("/admin/broadcast", post(broadcast::send_broadcast))
+        .route("/admin/events/switch", post(admin::switch_event))
+        .route("/admin/users/get/all", get(admin::get_scout_stats))
         .route("/scout/get/unpitted", get(admin::get_unpitscouted_teams))
+        .route("/scout/get/pitstatus", get(admin::get_pit_status))
+        .route("/stats/teams", get(stats::get_team_stats))
+        .route("/images", get(gallery::list_images))
+        .route(
+            "/images/:name",
+            patch(gallery::update_image).delete(gallery::delete_image),
+        )
+        .route("/images/:name/primary", put(gallery::set_primary_image))
+        .route("/shifts", get(shifts::get_shifts))
+        .route("/admin/shifts", post(shifts::new_shift))
+        .route("/admin/shifts/:id", delete(shifts::delete_shift))
         .route("/vapid", get(webpush::vapid))
         .route("/register", post(webpush::register))
+        .route("/unregister", post(webpush::unregister))
+        .route("/push/subscriptions", get(webpush::get_subscriptions))
+        .route(
+            "/notifications/preferences",
+            get(webpush::get_preferences).put(webpush::set_preferences),
+        )
         .layer(layer)
+        .layer(Extension(io))
         .with_state(state)
         .layer(CorsLayer::permissive())
 }
@@ -141,8 +235,26 @@ async fn health() -> Result<impl IntoResponse, Infallible> {
     Ok(())
 }
 
-async fn dummy_data() -> Result<Json<TeamMatch>, Infallible> {
-    let team_match = TeamMatch::default();
+async fn dummy_data(
+    State(state): State<model::AppState>,
+) -> Result<Json<model::TeamMatch>, StatusCode> {
+    // The current event's game, falling back to the newest season before an event is picked
+    let event_key = admin::get_current_event(&state.db).await.ok();
+    let season = event_key
+        .as_deref()
+        .and_then(|event_key| state.seasons.for_key(event_key))
+        .or_else(|| state.seasons.latest())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let team_match = model::TeamMatch {
+        id: 0,
+        match_key: String::new(),
+        team_key: String::new(),
+        event_key: event_key.unwrap_or_default(),
+        scout_id: String::new(),
+        season: season.year,
+        data: season.default_data().into(),
+    };
 
     Ok(Json(team_match))
 }
@@ -1,16 +1,17 @@
-use axum::response::sse::Event;
-
-
-
 use std::collections::HashMap;
-use std::convert::Infallible;
 
 use std::sync::Arc;
 
-use tokio::sync::watch::Sender;
 use tokio::sync::Mutex;
 
-use crate::{ws};
+use crate::{
+    events::EventBus,
+    season::Seasons,
+    storage::{ImageStore, LocalStore},
+    upload::ImageWorkers,
+    webpush::PushService,
+    ws,
+};
 use reqwest::Client as ReqwestClient;
 use serde::{Deserialize, Serialize};
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
@@ -25,18 +26,81 @@ impl Db {
     pub async fn new(db_url: String) -> Result<Self, sqlx::Error> {
         let pool: Pool<Postgres> = PgPoolOptions::new().connect(&db_url).await?;
 
+        sqlx::migrate!().run(&pool).await?;
+
         Ok(Db { pool })
     }
 }
 
+#[cfg(test)]
+impl Db {
+    /// A freshly migrated database of its own for one test. Tests that need Postgres are
+    /// skipped by returning early on None when `DATABASE_URL` isn't set.
+    pub async fn for_test() -> Option<Self> {
+        let url = std::env::var("DATABASE_URL").ok()?;
+        let name = format!("scout_test_{}", uuid::Uuid::new_v4().simple());
+
+        let server = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&url)
+            .await
+            .expect("DATABASE_URL is unreachable");
+        sqlx::query(&format!("CREATE DATABASE {name}"))
+            .execute(&server)
+            .await
+            .expect("Failed to create a test database");
+        server.close().await;
+
+        let options = url
+            .parse::<sqlx::postgres::PgConnectOptions>()
+            .expect("Invalid DATABASE_URL")
+            .database(&name);
+        let pool = PgPoolOptions::new()
+            .connect_with(options)
+            .await
+            .expect("Failed to connect to the test database");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .expect("Migrations failed on a fresh database");
+
+        Some(Db { pool })
+    }
+
+    /// Drops a database made by `for_test`
+    pub async fn drop_test(self) {
+        let name: String = sqlx::query_scalar("SELECT current_database()")
+            .fetch_one(&self.pool)
+            .await
+            .unwrap();
+        self.pool.close().await;
+
+        let server = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&std::env::var("DATABASE_URL").unwrap())
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP DATABASE IF EXISTS {name} WITH (FORCE)"))
+            .execute(&server)
+            .await
+            .unwrap();
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
     pub db: Db,
     pub ctx: ReqwestClient,
-    pub sse_upstream: Arc<Mutex<Sender<Result<Event, Infallible>>>>,
+    pub events: Arc<EventBus>,
     pub queue_manager: Arc<Mutex<ws::QueueManager>>,
+    pub seasons: Arc<Seasons>,
+    pub image_store: Arc<dyn ImageStore>,
+    pub image_cache: Arc<LocalStore>,
+    pub image_workers: ImageWorkers,
+    pub push: PushService,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct RoboQueue {
     pub match_keys: Vec<String>,
@@ -52,10 +116,10 @@ pub struct User {
     pub name: String,
     pub is_notify: bool,
     pub is_admin: bool,
-    pub endpoint: Option<String>,
-    pub p256dh: Option<String>,
-    pub auth: Option<String>,
     pub access_token: String,
+    pub notify_assignment: bool,
+    pub notify_shift: bool,
+    pub notify_broadcast: bool,
 }
 
 impl User {
@@ -64,9 +128,6 @@ impl User {
         name: String,
         is_notify: bool,
         is_admin: bool,
-        endpoint: Option<String>,
-        p256dh: Option<String>,
-        auth: Option<String>,
         access_token: String,
     ) -> Self {
         User {
@@ -74,9 +135,9 @@ impl User {
             name,
             is_notify,
             is_admin,
-            endpoint,
-            p256dh,
-            auth,
+            notify_assignment: true,
+            notify_shift: true,
+            notify_broadcast: true,
             access_token,
         }
     }
@@ -87,6 +148,7 @@ pub struct AuthRequest {
     pub code: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SlackClaims {
     pub exp: i64,
@@ -95,6 +157,11 @@ pub struct SlackClaims {
 
 // Database model
 
+/// Match keys are prefixed with their event, e.g. `2024orsal_qm67`
+pub fn event_key_of(match_key: &str) -> &str {
+    match_key.split('_').next().unwrap_or(match_key)
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ScoutEventTeam {
     pub event_key: String,
@@ -102,31 +169,23 @@ pub struct ScoutEventTeam {
     pub scout_id: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow, Default)]
+/// A match scouting form, the scouted fields are kept in `data` and checked against the season config.
+/// Serialized flat so the form fields sit beside the keys.
+#[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
 pub struct TeamMatch {
+    #[serde(default)]
     pub id: i32,
     pub match_key: String,
     pub team_key: String,
-    pub is_fielded: bool,
-    pub is_leave_start: bool,
-    pub auto_speaker_succeed: i16,
-    pub auto_speaker_missed: i16,
-    pub auto_amp_succeed: i16,
-    pub auto_amp_missed: i16,
-    pub auto_piece_succeed: i16,
-    pub auto_piece_missed: i16,
-    pub tele_speaker_succeed: i16,
-    pub tele_speaker_missed: i16,
-    pub tele_amp_succeed: i16,
-    pub tele_amp_missed: i16,
-    pub trap_succeed: bool,
-    pub trap_missed: bool,
-    pub stage: Stage,
-    pub skill: i16,
-    pub notes: String,
-    pub is_broke: bool,
-    pub is_died: bool,
+    #[serde(default)]
+    pub event_key: String,
+    /// Whoever is logged in on the socket, never taken from the form
+    #[serde(skip_deserializing)]
     pub scout_id: String,
+    #[serde(default)]
+    pub season: i32,
+    #[serde(flatten)]
+    pub data: serde_json::Value,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
@@ -135,18 +194,27 @@ pub struct Team {
     pub nickname: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
+pub struct Event {
+    pub event_key: String,
+    pub steam_url: Option<String>,
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
 pub struct Match {
     pub match_key: String,
     pub event_key: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
 pub struct Video {
     pub match_key: String,
     pub url: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
 pub struct TBAMatch {
     pub match_key: String,
@@ -161,52 +229,103 @@ pub struct TBAMatch {
     pub video: i64,
 }
 
-// Pitscouting
+// Pitscouting, fields left as None are kept from earlier visits to the same team
 #[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
 pub struct TeamEvent {
+    #[serde(default)]
     pub id: i64,
     pub team_key: String,
     pub event_key: String,
-    pub width: i16,
-    pub length: i16,
-    pub is_short: bool,
-    pub is_camera: bool,
-    pub drivetrain: DriveTrain,
-    pub is_ground_intake: bool,
-    pub is_chute_intake: bool,
-    pub polish: Polish,
+    pub width: Option<i16>,
+    pub length: Option<i16>,
+    pub is_short: Option<bool>,
+    pub is_camera: Option<bool>,
+    #[sqlx(rename = "drivetrain_enum")]
+    pub drivetrain: Option<DriveTrain>,
+    pub is_ground_intake: Option<bool>,
+    pub is_chute_intake: Option<bool>,
+    pub polish: Option<Polish>,
+    /// Filled in from the logged in user, scouts don't send it
+    #[serde(default)]
     pub scout_id: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PitStatus {
+    NotStarted,
+    Partial,
+    Complete,
+}
+
+impl TryFrom<String> for PitStatus {
+    type Error = String;
+
+    fn try_from(status: String) -> Result<Self, Self::Error> {
+        match status.as_str() {
+            "not_started" => Ok(PitStatus::NotStarted),
+            "partial" => Ok(PitStatus::Partial),
+            "complete" => Ok(PitStatus::Complete),
+            _ => Err(format!("Unknown pit status: {status}")),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
+pub struct TeamPitStatus {
+    pub team_key: String,
+    pub nickname: String,
+    #[sqlx(try_from = "String")]
+    pub status: PitStatus,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
 pub struct Image {
     pub id: i64,
+    pub name: String,
     pub event_key: String,
     pub team_key: String,
     pub url: String,
-    pub scout_id: i64,
+    pub scout_id: String,
+    pub team_event_id: Option<i64>,
+    #[serde(skip)]
+    pub storage_key: String,
+    pub caption: Option<String>,
+    pub tags: Vec<String>,
+    pub is_primary: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// An earlier photo of the same team that looks almost identical
+    pub near_duplicate_of: Option<String>,
 }
 
+// Edit history for TeamMatches and TeamEvents, rows are stored as JSON snapshots
 #[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
-pub struct EventState {
-    pub event_key: String,
-    pub next_match: Option<String>,
-    pub last_match: Option<String>,
+pub struct Revision {
+    pub id: i64,
+    pub table_name: String,
+    pub record_id: i64,
+    pub action: String,
+    pub editor_id: String,
+    pub edited_at: chrono::DateTime<chrono::Utc>,
+    pub old_value: Option<serde_json::Value>,
+    pub new_value: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, sqlx::Type)]
-#[sqlx(rename_all = "lowercase")]
-pub enum Stage {
-    OnState,
-    Park,
-    NotAttempted,
-    Failed,
+#[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
+pub struct Shift {
+    pub id: i64,
+    pub event_key: String,
+    pub scout_id: String,
+    pub starts_at: chrono::DateTime<chrono::Utc>,
+    pub ends_at: chrono::DateTime<chrono::Utc>,
+    pub notified_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
-impl Default for Stage {
-    fn default() -> Self {
-        Stage::NotAttempted
-    }
+#[derive(Debug, Deserialize, Serialize, Clone, sqlx::FromRow)]
+pub struct EventState {
+    pub event_key: String,
+    pub next_match: Option<String>,
+    pub last_match: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, sqlx::Type)]
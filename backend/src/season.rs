@@ -0,0 +1,415 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::info;
+
+/// Every scouting form for a game, loaded from `SEASONS_DIR` at startup
+#[derive(Debug, Default)]
+pub struct Seasons {
+    seasons: HashMap<i32, SeasonConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeasonConfig {
+    pub year: i32,
+    pub name: String,
+    pub fields: Vec<FieldDef>,
+    #[serde(default)]
+    pub accuracy: Vec<AccuracyPair>,
+}
+
+/// Made and missed fields reported together as a success rate, e.g. speaker shots
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccuracyPair {
+    pub name: String,
+    pub succeed: String,
+    pub missed: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldDef {
+    pub name: String,
+    pub phase: Phase,
+    #[serde(default = "required_default")]
+    pub required: bool,
+    #[serde(flatten)]
+    pub kind: FieldKind,
+}
+
+fn required_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Pre,
+    Auto,
+    Tele,
+    Endgame,
+    Post,
+}
+
+/// Field types, `points` are the scoring weights used to estimate a robot's contribution
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FieldKind {
+    Count {
+        min: i64,
+        max: i64,
+        #[serde(default)]
+        points: f64,
+    },
+    Bool {
+        #[serde(default)]
+        points: f64,
+    },
+    Rating {
+        min: i64,
+        max: i64,
+    },
+    Choice {
+        options: Vec<String>,
+        #[serde(default)]
+        points: HashMap<String, f64>,
+    },
+    Text {
+        max_length: Option<usize>,
+    },
+}
+
+/// Seasons are keyed by the year every event and match key starts with
+pub fn season_of(key: &str) -> Option<i32> {
+    key.get(0..4)?.parse().ok()
+}
+
+impl Seasons {
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut seasons = HashMap::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+
+            let config: SeasonConfig = serde_json::from_str(&fs::read_to_string(&path)?)
+                .map_err(|err| anyhow::anyhow!("Invalid season config {:?}: {}", path, err))?;
+            config
+                .check()
+                .map_err(|err| anyhow::anyhow!("Invalid season config {:?}: {}", path, err))?;
+            info!("Loaded {} season config {}", config.year, config.name);
+            seasons.insert(config.year, config);
+        }
+
+        Ok(Seasons { seasons })
+    }
+
+    pub fn get(&self, season: i32) -> Option<&SeasonConfig> {
+        self.seasons.get(&season)
+    }
+
+    pub fn for_key(&self, key: &str) -> Option<&SeasonConfig> {
+        self.get(season_of(key)?)
+    }
+
+    /// The newest season loaded, for when there's no event to go by
+    pub fn latest(&self) -> Option<&SeasonConfig> {
+        self.seasons.values().max_by_key(|season| season.year)
+    }
+}
+
+impl SeasonConfig {
+    pub fn field(&self, name: &str) -> Option<&FieldDef> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        self.check_fields()?;
+        self.check_accuracy()
+    }
+
+    /// Every field needs a value for `default_data` to start from
+    fn check_fields(&self) -> Result<(), String> {
+        for field in self.fields.iter() {
+            match &field.kind {
+                FieldKind::Count { min, max, .. } | FieldKind::Rating { min, max } if min > max => {
+                    return Err(format!("{} has min {min} above max {max}", field.name))
+                }
+                FieldKind::Choice { options, .. } if options.is_empty() => {
+                    return Err(format!("{} has no options to choose from", field.name))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Accuracy pairs have to name fields that can be counted
+    fn check_accuracy(&self) -> Result<(), String> {
+        for pair in self.accuracy.iter() {
+            for name in [&pair.succeed, &pair.missed] {
+                match self.field(name).map(|field| &field.kind) {
+                    Some(FieldKind::Count { .. } | FieldKind::Bool { .. }) => {}
+                    Some(_) => {
+                        return Err(format!(
+                            "Accuracy {} uses {name}, which isn't a count or bool",
+                            pair.name
+                        ))
+                    }
+                    None => {
+                        return Err(format!("Accuracy {} uses unknown field {name}", pair.name))
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks submitted data against the form, returning it with choices normalized to their configured spelling
+    pub fn validate(&self, data: &Value) -> Result<Map<String, Value>, String> {
+        let data = match data.as_object() {
+            Some(data) => data,
+            None => return Err("Scouting data must be an object".to_string()),
+        };
+
+        if let Some(unknown) = data.keys().find(|key| self.field(key).is_none()) {
+            return Err(format!("Unknown field {unknown} for {} season", self.year));
+        }
+
+        let mut valid = Map::new();
+        for field in self.fields.iter() {
+            match data.get(&field.name) {
+                None | Some(Value::Null) if field.required => {
+                    return Err(format!("Missing field {}", field.name))
+                }
+                None | Some(Value::Null) => {}
+                Some(value) => {
+                    valid.insert(field.name.clone(), field.check(value)?);
+                }
+            }
+        }
+
+        Ok(valid)
+    }
+
+    /// A blank form, every field at its lowest value
+    pub fn default_data(&self) -> Map<String, Value> {
+        self.fields
+            .iter()
+            .map(|field| {
+                let value = match &field.kind {
+                    FieldKind::Count { min, .. } | FieldKind::Rating { min, .. } => {
+                        Value::from(*min)
+                    }
+                    FieldKind::Bool { .. } => Value::Bool(false),
+                    FieldKind::Choice { options, .. } => Value::from(options[0].clone()),
+                    FieldKind::Text { .. } => Value::from(""),
+                };
+                (field.name.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Estimated points scored, from the weights in the config
+    pub fn score(&self, data: &Map<String, Value>) -> f64 {
+        self.fields
+            .iter()
+            .map(|field| match (&field.kind, data.get(&field.name)) {
+                (FieldKind::Count { points, .. }, Some(value)) => {
+                    points * value.as_f64().unwrap_or(0.0)
+                }
+                (FieldKind::Bool { points }, Some(Value::Bool(true))) => *points,
+                (FieldKind::Choice { points, .. }, Some(Value::String(choice))) => {
+                    points.get(choice).copied().unwrap_or(0.0)
+                }
+                _ => 0.0,
+            })
+            .sum()
+    }
+}
+
+impl FieldDef {
+    fn check(&self, value: &Value) -> Result<Value, String> {
+        let name = &self.name;
+        match &self.kind {
+            FieldKind::Count { min, max, .. } | FieldKind::Rating { min, max } => {
+                match value.as_i64() {
+                    Some(count) if (*min..=*max).contains(&count) => Ok(value.clone()),
+                    Some(count) => Err(format!("{name} is {count}, must be {min} to {max}")),
+                    None => Err(format!("{name} must be a whole number")),
+                }
+            }
+            FieldKind::Bool { .. } => match value.is_boolean() {
+                true => Ok(value.clone()),
+                false => Err(format!("{name} must be true or false")),
+            },
+            FieldKind::Choice { options, .. } => {
+                let choice = value.as_str().unwrap_or_default();
+                match options
+                    .iter()
+                    .find(|option| option.eq_ignore_ascii_case(choice))
+                {
+                    Some(option) => Ok(Value::from(option.clone())),
+                    None => Err(format!("{name} must be one of {}", options.join(", "))),
+                }
+            }
+            FieldKind::Text { max_length } => match (value.as_str(), max_length) {
+                (Some(text), Some(max_length)) if text.chars().count() > *max_length => {
+                    Err(format!("{name} is longer than {max_length} characters"))
+                }
+                (Some(_), _) => Ok(value.clone()),
+                (None, _) => Err(format!("{name} must be text")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> SeasonConfig {
+        serde_json::from_value(json!({
+            "year": 2024,
+            "name": "Test",
+            "fields": [
+                { "name": "speaker", "phase": "tele", "type": "count", "min": 0, "max": 10, "points": 2 },
+                { "name": "missed", "phase": "tele", "type": "count", "min": 0, "max": 10 },
+                { "name": "trap", "phase": "endgame", "type": "bool", "points": 5 },
+                { "name": "stage", "phase": "endgame", "type": "choice", "options": ["park", "onstage"], "points": { "onstage": 3 } },
+                { "name": "skill", "phase": "post", "type": "rating", "min": 1, "max": 5 },
+                { "name": "notes", "phase": "post", "type": "text", "max_length": 5, "required": false }
+            ],
+            "accuracy": [{ "name": "speaker", "succeed": "speaker", "missed": "missed" }]
+        }))
+        .unwrap()
+    }
+
+    fn submission() -> Value {
+        json!({ "speaker": 3, "missed": 1, "trap": true, "stage": "OnStage", "skill": 4 })
+    }
+
+    #[test]
+    fn loads_the_shipped_seasons() {
+        let seasons = Seasons::load("seasons").unwrap();
+        assert_eq!(seasons.for_key("2024casj_qm1").unwrap().name, "Crescendo");
+        assert_eq!(seasons.latest().unwrap().year, 2024);
+        assert!(seasons.for_key("2019casj").is_none());
+        assert!(seasons.for_key("qm").is_none());
+    }
+
+    #[test]
+    fn shipped_season_accepts_the_scouting_form() {
+        let seasons = Seasons::load("seasons").unwrap();
+        let config = seasons.get(2024).unwrap();
+        let mut data = config.default_data();
+        data.extend([
+            ("stage".to_string(), json!("Park")),
+            ("trap_succeed".to_string(), json!(true)),
+            ("trap_missed".to_string(), json!(false)),
+        ]);
+
+        let valid = config.validate(&Value::Object(data)).unwrap();
+        assert_eq!(valid["stage"], "park");
+    }
+
+    #[test]
+    fn rejects_fields_without_a_default() {
+        let mut config = config();
+        config.fields[4].kind = FieldKind::Rating { min: 5, max: 1 };
+        assert_eq!(config.check().unwrap_err(), "skill has min 5 above max 1");
+
+        let mut config = self::config();
+        config.fields[3].kind = FieldKind::Choice {
+            options: vec![],
+            points: HashMap::new(),
+        };
+        assert_eq!(
+            config.check().unwrap_err(),
+            "stage has no options to choose from"
+        );
+    }
+
+    #[test]
+    fn validates_submissions() {
+        let config = config();
+
+        let valid = config.validate(&submission()).unwrap();
+        assert_eq!(valid["stage"], "onstage");
+        assert!(!valid.contains_key("notes"));
+
+        let invalid = |change: Value| {
+            let mut data = submission();
+            data.as_object_mut()
+                .unwrap()
+                .extend(change.as_object().unwrap().clone());
+            config.validate(&data).unwrap_err()
+        };
+        assert_eq!(
+            invalid(json!({ "speaker": 11 })),
+            "speaker is 11, must be 0 to 10"
+        );
+        assert_eq!(
+            invalid(json!({ "speaker": 1.5 })),
+            "speaker must be a whole number"
+        );
+        assert_eq!(
+            invalid(json!({ "trap": "yes" })),
+            "trap must be true or false"
+        );
+        assert_eq!(
+            invalid(json!({ "stage": "hang" })),
+            "stage must be one of park, onstage"
+        );
+        assert_eq!(invalid(json!({ "skill": null })), "Missing field skill");
+        assert_eq!(
+            invalid(json!({ "notes": "too long" })),
+            "notes is longer than 5 characters"
+        );
+        assert!(config
+            .validate(&json!({ "speaker": 3, "missed": 1, "trap": true, "stage": "park", "skill": 4, "notes": "ééééé" }))
+            .is_ok());
+        assert_eq!(
+            invalid(json!({ "climb": 1 })),
+            "Unknown field climb for 2024 season"
+        );
+        assert!(config.validate(&json!([])).is_err());
+    }
+
+    #[test]
+    fn default_data_is_valid() {
+        let config = config();
+        let defaults = config.default_data();
+        assert_eq!(defaults["skill"], 1);
+        assert!(config.validate(&Value::Object(defaults)).is_ok());
+    }
+
+    #[test]
+    fn scores_with_the_configured_points() {
+        let config = config();
+        let data = config.validate(&submission()).unwrap();
+        // 3 speaker * 2 + trap 5 + onstage 3, missed shots and ratings are worth nothing
+        assert_eq!(config.score(&data), 14.0);
+        assert_eq!(config.score(&config.default_data()), 0.0);
+    }
+
+    #[test]
+    fn rejects_accuracy_on_fields_that_cant_be_counted() {
+        let mut config = config();
+        config.accuracy[0].missed = "notes".to_string();
+        assert!(config
+            .check_accuracy()
+            .unwrap_err()
+            .contains("isn't a count or bool"));
+        config.accuracy[0].missed = "shots".to_string();
+        assert!(config
+            .check_accuracy()
+            .unwrap_err()
+            .contains("unknown field shots"));
+    }
+}
@@ -0,0 +1,152 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    admin::{check_admin_auth, resolve_event, EventQuery},
+    error::ApiError,
+    model::{AppState, Db, Shift, User},
+    webpush::{PushMessage, PushService},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct NewShift {
+    event_key: Option<String>,
+    scout_id: String,
+    starts_at: DateTime<Utc>,
+    ends_at: DateTime<Utc>,
+}
+
+#[axum::debug_handler]
+pub async fn get_shifts(
+    State(state): State<AppState>,
+    Query(query): Query<EventQuery>,
+) -> Result<Json<Vec<Shift>>, (StatusCode, String)> {
+    let event_key = resolve_event(&state.db, query).await?;
+
+    match sqlx::query_as::<_, Shift>(
+        "SELECT * FROM \"Shifts\" WHERE event_key = $1 ORDER BY starts_at",
+    )
+    .bind(event_key)
+    .fetch_all(&state.db.pool)
+    .await
+    {
+        Ok(shifts) => Ok(Json(shifts)),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn new_shift(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(shift): Json<NewShift>,
+) -> Result<Json<Shift>, (StatusCode, String)> {
+    check_admin_auth(&state.db, headers).await?;
+
+    if shift.ends_at <= shift.starts_at {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Shifts must end after they start".to_string(),
+        ));
+    }
+
+    let event_key = resolve_event(
+        &state.db,
+        EventQuery {
+            event_key: shift.event_key,
+        },
+    )
+    .await?;
+
+    match sqlx::query_as::<_, Shift>("INSERT INTO \"Shifts\" (event_key, scout_id, starts_at, ends_at) VALUES ($1, $2, $3, $4) RETURNING *")
+        .bind(event_key)
+        .bind(shift.scout_id)
+        .bind(shift.starts_at)
+        .bind(shift.ends_at)
+        .fetch_one(&state.db.pool)
+        .await
+    {
+        Ok(shift) => {
+            info!("Added shift {} for {}", shift.id, shift.scout_id);
+            Ok(Json(shift))
+        }
+        Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+            Err((StatusCode::NOT_FOUND, "No such scout".to_string()))
+        }
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn delete_shift(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_admin_auth(&state.db, headers).await?;
+
+    match sqlx::query("DELETE FROM \"Shifts\" WHERE id = $1")
+        .bind(id)
+        .execute(&state.db.pool)
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => {
+            Err((StatusCode::NOT_FOUND, format!("No shift {id}")))
+        }
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+/// Minutes before a shift that its scout is reminded
+fn shift_reminder_lead() -> i32 {
+    std::env::var("SHIFT_REMINDER_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or(5)
+}
+
+/// Reminds scouts of upcoming shifts, each shift is only claimed by one pass so nobody is notified twice
+pub async fn shift_reminders(db: Db, push: PushService) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+
+    loop {
+        interval.tick().await;
+
+        let due: Vec<(String, DateTime<Utc>, String)> = match sqlx::query_as("UPDATE \"Shifts\" SET notified_at = now() WHERE notified_at IS NULL AND starts_at <= now() + make_interval(mins => $1) AND ends_at > now() RETURNING event_key, starts_at, scout_id")
+            .bind(shift_reminder_lead())
+            .fetch_all(&db.pool)
+            .await
+        {
+            Ok(due) => due,
+            Err(err) => {
+                error!("Failed to check for upcoming shifts: {}", err);
+                continue;
+            }
+        };
+
+        for (event_key, starts_at, scout_id) in due {
+            match sqlx::query_as::<_, User>("SELECT * FROM \"Users\" WHERE id = $1")
+                .bind(&scout_id)
+                .fetch_one(&db.pool)
+                .await
+            {
+                Ok(user) => push.notify_user(
+                    user,
+                    PushMessage::ShiftStart {
+                        event_key,
+                        starts_at,
+                    },
+                ),
+                Err(err) => error!("Failed to look up scout {} for shift: {}", scout_id, err),
+            }
+        }
+    }
+}
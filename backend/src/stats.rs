@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    admin::{resolve_event, EventQuery},
+    error::ApiError,
+    model::AppState,
+    season::{FieldKind, Phase, SeasonConfig},
+};
+
+/// Matches in the trend when `last` is left out
+const DEFAULT_TREND_MATCHES: usize = 5;
+
+/// A match key and the scouting data recorded for it
+type ScoutedMatch = (String, Map<String, Value>);
+
+#[derive(Debug, Deserialize)]
+pub struct TeamStatsQuery {
+    event_key: Option<String>,
+    team_key: Option<String>,
+    /// How many of the latest matches the trend covers
+    last: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldStats {
+    pub mean: f64,
+    pub median: f64,
+    pub max: f64,
+    pub std_dev: f64,
+}
+
+impl FieldStats {
+    fn of(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+
+        let count = sorted.len() as f64;
+        let mean = sorted.iter().sum::<f64>() / count;
+        let middle = sorted.len() / 2;
+        let median = if sorted.len().is_multiple_of(2) {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        } else {
+            sorted[middle]
+        };
+        let variance = sorted
+            .iter()
+            .map(|value| (value - mean).powi(2))
+            .sum::<f64>()
+            / count;
+
+        Some(FieldStats {
+            mean,
+            median,
+            max: sorted[sorted.len() - 1],
+            std_dev: variance.sqrt(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Accuracy {
+    pub succeeded: f64,
+    pub attempted: f64,
+    /// Left out when nothing was attempted
+    pub rate: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MatchScore {
+    pub match_key: String,
+    pub score: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Trend {
+    pub matches: Vec<MatchScore>,
+    pub recent_mean: f64,
+    /// Recent mean minus the mean over every match, positive when a team is improving
+    pub change: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TeamStats {
+    pub team_key: String,
+    pub matches: usize,
+    /// Estimated points per match, from the weights in the season config
+    pub score: Option<FieldStats>,
+    /// Count and rating fields, grouped by the part of the match they happen in
+    pub phases: BTreeMap<Phase, BTreeMap<String, FieldStats>>,
+    /// How often each bool field was true, e.g. broke or died
+    pub rates: BTreeMap<String, f64>,
+    /// How often each option was picked, e.g. stage outcomes
+    pub choices: BTreeMap<String, BTreeMap<String, f64>>,
+    pub accuracy: BTreeMap<String, Accuracy>,
+    pub trend: Trend,
+}
+
+fn field_value(data: &Map<String, Value>, name: &str) -> f64 {
+    match data.get(name) {
+        Some(Value::Bool(true)) => 1.0,
+        Some(value) => value.as_f64().unwrap_or(0.0),
+        None => 0.0,
+    }
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> f64 {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f64
+    }
+}
+
+/// `matches` are one team's scouting data, oldest first
+fn team_stats(
+    season: &SeasonConfig,
+    team_key: String,
+    matches: Vec<ScoutedMatch>,
+    last: usize,
+) -> TeamStats {
+    let mut phases: BTreeMap<Phase, BTreeMap<String, FieldStats>> = BTreeMap::new();
+    let mut rates = BTreeMap::new();
+    let mut choices = BTreeMap::new();
+
+    for field in season.fields.iter() {
+        match &field.kind {
+            FieldKind::Count { .. } | FieldKind::Rating { .. } => {
+                let values: Vec<f64> = matches
+                    .iter()
+                    .map(|(_, data)| field_value(data, &field.name))
+                    .collect();
+                if let Some(stats) = FieldStats::of(&values) {
+                    phases
+                        .entry(field.phase)
+                        .or_default()
+                        .insert(field.name.clone(), stats);
+                }
+            }
+            FieldKind::Bool { .. } => {
+                rates.insert(
+                    field.name.clone(),
+                    mean(
+                        matches
+                            .iter()
+                            .map(|(_, data)| field_value(data, &field.name)),
+                    ),
+                );
+            }
+            FieldKind::Choice { options, .. } => {
+                let picked: BTreeMap<String, f64> = options
+                    .iter()
+                    .map(|option| {
+                        let rate = mean(matches.iter().map(|(_, data)| {
+                            match data.get(&field.name).and_then(Value::as_str) {
+                                Some(choice) if choice == option => 1.0,
+                                _ => 0.0,
+                            }
+                        }));
+                        (option.clone(), rate)
+                    })
+                    .collect();
+                choices.insert(field.name.clone(), picked);
+            }
+            FieldKind::Text { .. } => {}
+        }
+    }
+
+    let accuracy = season
+        .accuracy
+        .iter()
+        .map(|pair| {
+            let succeeded: f64 = matches
+                .iter()
+                .map(|(_, data)| field_value(data, &pair.succeed))
+                .sum();
+            let missed: f64 = matches
+                .iter()
+                .map(|(_, data)| field_value(data, &pair.missed))
+                .sum();
+            let attempted = succeeded + missed;
+
+            (
+                pair.name.clone(),
+                Accuracy {
+                    succeeded,
+                    attempted,
+                    rate: (attempted > 0.0).then_some(succeeded / attempted),
+                },
+            )
+        })
+        .collect();
+
+    let mut scores: Vec<MatchScore> = matches
+        .iter()
+        .map(|(match_key, data)| MatchScore {
+            match_key: match_key.clone(),
+            score: season.score(data),
+        })
+        .collect();
+    let score_values: Vec<f64> = scores.iter().map(|score| score.score).collect();
+    let overall = mean(score_values.iter().copied());
+    let recent = scores.split_off(scores.len().saturating_sub(last));
+    let recent_mean = mean(recent.iter().map(|score| score.score));
+
+    TeamStats {
+        team_key,
+        matches: matches.len(),
+        score: FieldStats::of(&score_values),
+        phases,
+        rates,
+        choices,
+        accuracy,
+        trend: Trend {
+            matches: recent,
+            recent_mean,
+            change: recent_mean - overall,
+        },
+    }
+}
+
+/// Summaries of every team scouted at an event, or just `team_key`
+#[axum::debug_handler]
+pub async fn get_team_stats(
+    State(state): State<AppState>,
+    Query(query): Query<TeamStatsQuery>,
+) -> Result<Json<Vec<TeamStats>>, (StatusCode, String)> {
+    let event_key = resolve_event(
+        &state.db,
+        EventQuery {
+            event_key: query.event_key,
+        },
+    )
+    .await?;
+
+    let Some(season) = state.seasons.for_key(&event_key) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("No season config for event {event_key}"),
+        ));
+    };
+
+    let rows = match sqlx::query_as::<_, (String, String, Value)>(
+        "SELECT team_key, match_key, data FROM \"TeamMatches\" WHERE event_key = $1 AND deleted_at IS NULL AND ($2::TEXT IS NULL OR team_key = $2) ORDER BY team_key, created_at, id",
+    )
+    .bind(&event_key)
+    .bind(query.team_key)
+    .fetch_all(&state.db.pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(err) => return Err(ApiError::sqlx(err)),
+    };
+
+    let mut teams: BTreeMap<String, Vec<ScoutedMatch>> = BTreeMap::new();
+    for (team_key, match_key, data) in rows {
+        if let Value::Object(data) = data {
+            teams.entry(team_key).or_default().push((match_key, data));
+        }
+    }
+
+    let last = query.last.unwrap_or(DEFAULT_TREND_MATCHES);
+    Ok(Json(
+        teams
+            .into_iter()
+            .map(|(team_key, matches)| team_stats(season, team_key, matches, last))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::season::Seasons;
+
+    #[test]
+    fn summarizes_values() {
+        assert!(FieldStats::of(&[]).is_none());
+
+        let stats = FieldStats::of(&[4.0, 1.0, 3.0]).unwrap();
+        assert_eq!(stats.mean, 8.0 / 3.0);
+        assert_eq!(stats.median, 3.0);
+        assert_eq!(stats.max, 4.0);
+
+        // An even count takes the middle two for the median, std dev is the population's
+        let stats = FieldStats::of(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]).unwrap();
+        assert_eq!(stats.mean, 5.0);
+        assert_eq!(stats.median, 4.5);
+        assert_eq!(stats.max, 9.0);
+        assert_eq!(stats.std_dev, 2.0);
+
+        let stats = FieldStats::of(&[6.0]).unwrap();
+        assert_eq!((stats.median, stats.std_dev), (6.0, 0.0));
+    }
+
+    #[test]
+    fn summarizes_a_team() {
+        let seasons = Seasons::load("seasons").unwrap();
+        let season = seasons.get(2024).unwrap();
+        let scouted = |number: u32, speaker: i64, missed: i64, stage: &str| {
+            let mut data = season.default_data();
+            data.insert("tele_speaker_succeed".to_string(), Value::from(speaker));
+            data.insert("tele_speaker_missed".to_string(), Value::from(missed));
+            data.insert("stage".to_string(), Value::from(stage));
+            data.insert("is_broke".to_string(), Value::from(number == 1));
+            (format!("2024casj_qm{number}"), data)
+        };
+        let matches = vec![
+            scouted(1, 1, 3, "park"),
+            scouted(2, 2, 0, "onstage"),
+            scouted(3, 3, 0, "onstage"),
+            scouted(4, 4, 0, "onstage"),
+        ];
+
+        let stats = team_stats(season, "frc254".to_string(), matches, 2);
+
+        assert_eq!(stats.matches, 4);
+        assert_eq!(stats.phases[&Phase::Tele]["tele_speaker_succeed"].mean, 2.5);
+        assert_eq!(stats.rates["is_broke"], 0.25);
+        assert_eq!(stats.choices["stage"]["onstage"], 0.75);
+        assert_eq!(stats.choices["stage"]["failed"], 0.0);
+
+        let speaker = &stats.accuracy["tele_speaker"];
+        assert_eq!((speaker.succeeded, speaker.attempted), (10.0, 13.0));
+        assert_eq!(stats.accuracy["trap"].rate, None);
+
+        // 2 points a speaker note plus 1 for park or 3 onstage
+        let scores: Vec<f64> = stats.trend.matches.iter().map(|m| m.score).collect();
+        assert_eq!(scores, vec![9.0, 11.0]);
+        assert_eq!(stats.trend.recent_mean, 10.0);
+        assert_eq!(stats.trend.change, 10.0 - 7.5);
+        assert_eq!(stats.score.unwrap().max, 11.0);
+    }
+}
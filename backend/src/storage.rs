@@ -0,0 +1,179 @@
+use std::{io::ErrorKind, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use s3::{creds::Credentials, error::S3Error, Bucket, Region};
+use tracing::info;
+
+/// Where robot photos are kept, picked by `IMAGE_STORE` so photos can outlive the container
+#[async_trait]
+pub trait ImageStore: Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()>;
+
+    /// Returns None when nothing is stored under the key
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()>;
+}
+
+pub fn from_env() -> anyhow::Result<Arc<dyn ImageStore>> {
+    let kind = std::env::var("IMAGE_STORE").unwrap_or("local".to_string());
+
+    match kind.as_str() {
+        "local" => {
+            let dir = std::env::var("IMAGE_DIR").expect("IMAGE_DIR is not set");
+            info!("Storing images in {}", dir);
+            Ok(Arc::new(LocalStore::new(dir)))
+        }
+        "s3" => {
+            let store = S3Store::from_env()?;
+            info!("Storing images in bucket {}", store.bucket.name);
+            Ok(Arc::new(store))
+        }
+        _ => Err(anyhow::anyhow!(
+            "Unknown IMAGE_STORE {kind}, expected local or s3"
+        )),
+    }
+}
+
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalStore { dir: dir.into() }
+    }
+
+    /// Removes every key under `prefix/`, such as all cached derivatives of one image
+    pub async fn delete_dir(&self, prefix: &str) -> anyhow::Result<()> {
+        match tokio::fs::remove_dir_all(self.dir.join(prefix)).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ImageStore for LocalStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let path = self.dir.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.dir.join(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(self.dir.join(key)).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Any S3 compatible service, set `S3_ENDPOINT` to use something other than AWS such as a local MinIO
+pub struct S3Store {
+    bucket: Bucket,
+}
+
+impl S3Store {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let name = std::env::var("S3_BUCKET").expect("S3_BUCKET is not set");
+        let region_name = std::env::var("S3_REGION").unwrap_or("us-east-1".to_string());
+        let access_key = std::env::var("S3_ACCESS_KEY").ok();
+        let secret_key = std::env::var("S3_SECRET_KEY").ok();
+
+        let credentials = Credentials::new(
+            access_key.as_deref(),
+            secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )?;
+
+        let bucket = match std::env::var("S3_ENDPOINT") {
+            Ok(endpoint) => {
+                let region = Region::Custom {
+                    region: region_name,
+                    endpoint,
+                };
+                // MinIO and most self hosted stores don't support virtual host style buckets
+                Bucket::new(&name, region, credentials)?.with_path_style()
+            }
+            Err(_) => Bucket::new(&name, region_name.parse()?, credentials)?,
+        };
+
+        Ok(S3Store { bucket })
+    }
+}
+
+#[async_trait]
+impl ImageStore for S3Store {
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let response = self.bucket.put_object(key, &data).await?;
+        match response.status_code() {
+            200..=299 => Ok(()),
+            status => Err(anyhow::anyhow!("S3 put of {key} failed with {status}")),
+        }
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.bucket.get_object(key).await {
+            Ok(response) => match response.status_code() {
+                200..=299 => Ok(Some(response.bytes().to_vec())),
+                404 => Ok(None),
+                status => Err(anyhow::anyhow!("S3 get of {key} failed with {status}")),
+            },
+            Err(S3Error::Http(404, _)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match self.bucket.delete_object(key).await {
+            Ok(_) | Err(S3Error::Http(404, _)) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_store_round_trips() {
+        let dir = std::env::temp_dir().join(format!("scout_images_{}", std::process::id()));
+        let store = LocalStore::new(&dir);
+
+        store.put("team/photo.jpg", vec![1, 2, 3]).await.unwrap();
+        store.put("team/cache/64x0.jpg", vec![4]).await.unwrap();
+        assert_eq!(
+            store.get("team/photo.jpg").await.unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(store.get("team/missing.jpg").await.unwrap(), None);
+
+        store.delete("team/photo.jpg").await.unwrap();
+        assert_eq!(store.get("team/photo.jpg").await.unwrap(), None);
+        // Deleting what's already gone isn't an error
+        store.delete("team/photo.jpg").await.unwrap();
+
+        store.delete_dir("team/cache").await.unwrap();
+        assert_eq!(store.get("team/cache/64x0.jpg").await.unwrap(), None);
+        store.delete_dir("team/cache").await.unwrap();
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -1,45 +1,55 @@
-use crate::model::{AppState, TeamEvent, TeamMatch, User};
-use axum::response::{
-    sse::{Event, KeepAlive, Sse},
-    IntoResponse,
+use crate::admin::get_user_from_headers;
+use crate::edit::{record_revision, TEAM_EVENTS};
+use crate::error::ApiError;
+use crate::events::SseReturn;
+use crate::model::{self, AppState, Db, PitStatus, ScoutEventTeam, TeamEvent, TeamMatch, User};
+use crate::upload;
+use axum::response::IntoResponse;
+use axum::{
+    body::Bytes,
+    extract::{Multipart, State},
+    http::StatusCode,
+    Json,
 };
-use axum::{extract::State, http::StatusCode};
-use futures::stream::Stream;
-use futures::StreamExt;
-use serde::{Deserialize, Serialize};
-
-use std::convert::Infallible;
-
-use tokio_stream::wrappers::WatchStream;
-use tracing::info;
-
-pub async fn admin_sse_connect(
-    State(state): State<AppState>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    info!("Admin connected to SSE stream");
-
-    let upstream = state.sse_upstream.lock().await;
-
-    let downstream = WatchStream::new(upstream.subscribe());
-
-    Sse::new(downstream).keep_alive(KeepAlive::default())
-}
+use http::HeaderMap;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use tracing::{error, info};
+
+/// Finds the season a form belongs to by its match key and checks the form against it
+pub fn validate_team_match(
+    state: &AppState,
+    form: &TeamMatch,
+) -> Result<(i32, Map<String, Value>), (StatusCode, String)> {
+    let season = match state.seasons.for_key(&form.match_key) {
+        Some(season) => season,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("No season config for match {}", form.match_key),
+            ))
+        }
+    };
 
-// SseReturn cannot implement AsRef(str), as the serialized value would go out of scope.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum SseReturn {
-    QueuedScout(String),
-    DeQueuedScout(String),
-    LoggedInScout(String),
-    TeamMatchScouted {
-        scout_name: String,
-        team_key: String,
-        match_key: String,
-    },
+    match season.validate(&form.data) {
+        Ok(data) => Ok((season.year, data)),
+        Err(err) => Err((StatusCode::BAD_REQUEST, err)),
+    }
 }
 
 pub async fn submit_team_match(state: &AppState, form: TeamMatch) -> impl IntoResponse {
-    let result = sqlx::query("INSERT INTO \"TeamMatches\" (match_key, team_key, is_fielded, is_leave_start, auto_speaker_succeed, auto_speaker_missed, auto_amp_succeed, auto_amp_missed, auto_piece_succeed, auto_piece_missed, tele_speaker_succeed, tele_speaker_missed, tele_amp_succeed, tele_amp_missed, trap_succeed, trap_missed, stage_enum, skill, notes, is_broke, is_died, scout_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22);").bind(form.match_key.clone()).bind(form.team_key.clone()).bind(form.is_fielded).bind(form.is_leave_start).bind(form.auto_speaker_succeed).bind(form.auto_speaker_missed).bind(form.auto_amp_succeed).bind(form.auto_amp_missed).bind(form.auto_piece_succeed).bind(form.auto_piece_missed).bind(form.tele_speaker_succeed).bind(form.tele_speaker_missed).bind(form.tele_amp_succeed).bind(form.tele_amp_missed).bind(form.trap_succeed).bind(form.trap_missed).bind(form.stage).bind(form.skill).bind(form.notes).bind(form.is_broke).bind(form.is_died).bind(form.scout_id.clone()).execute(&state.db.pool).await;
+    let (season, data) = match validate_team_match(state, &form) {
+        Ok(valid) => valid,
+        Err((status, err)) => {
+            error!("Rejected team match from {}: {}", form.scout_id, err);
+            return status;
+        }
+    };
+
+    let event_key = model::event_key_of(&form.match_key).to_string();
+
+    let result = sqlx::query("INSERT INTO \"TeamMatches\" (match_key, team_key, event_key, season, data, scout_id) VALUES ($1, $2, $3, $4, $5, $6);").bind(form.match_key.clone()).bind(form.team_key.clone()).bind(event_key).bind(season).bind(Value::from(data)).bind(form.scout_id.clone()).execute(&state.db.pool).await;
 
     match result {
         Ok(_) => {}
@@ -55,30 +65,177 @@ pub async fn submit_team_match(state: &AppState, form: TeamMatch) -> impl IntoRe
         Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
     };
 
-    let upstream = state.sse_upstream.lock().await;
-
-    let team_match = SseReturn::TeamMatchScouted {
+    state.events.publish(SseReturn::TeamMatchScouted {
         scout_name: user.name,
         team_key: form.team_key,
         match_key: form.match_key,
-    };
+    });
 
-    let event = Event::default()
-        .data(serde_json::to_string(&team_match).expect("SseReturn struct not serializable"));
+    StatusCode::OK
+}
 
-    let result = upstream.send(Ok(event));
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct PitSubmission {
+    pub id: i64,
+    #[sqlx(try_from = "String")]
+    pub status: PitStatus,
+}
 
-    match result {
-        Ok(_) => return StatusCode::OK,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+/// Inserts pit data for a team, or merges the given fields into the record already scouted at this event
+/// with a revision, so a later visit can be traced like any other edit
+pub async fn submit_pit_data(
+    db: &Db,
+    form: TeamEvent,
+) -> Result<PitSubmission, (StatusCode, String)> {
+    let mut tx = db.pool.begin().await.map_err(ApiError::sqlx)?;
+
+    let existing: Option<Value> = sqlx::query_scalar("SELECT to_jsonb(t) FROM \"TeamEvents\" t WHERE t.team_key = $1 AND t.event_key = $2 AND t.deleted_at IS NULL FOR UPDATE")
+        .bind(&form.team_key)
+        .bind(&form.event_key)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(ApiError::sqlx)?;
+    let editor_id = form.scout_id.clone();
+
+    let submission = sqlx::query_as::<_, PitSubmission>("INSERT INTO \"TeamEvents\" AS te (team_key, event_key, width, length, is_short, is_camera, drivetrain_enum, is_ground_intake, is_chute_intake, polish, scout_id) VALUES ($1, $2, $3, $4, $5, $6, $7,$8, $9, $10, $11) ON CONFLICT (team_key, event_key) WHERE deleted_at IS NULL DO UPDATE SET width = COALESCE(EXCLUDED.width, te.width), length = COALESCE(EXCLUDED.length, te.length), is_short = COALESCE(EXCLUDED.is_short, te.is_short), is_camera = COALESCE(EXCLUDED.is_camera, te.is_camera), drivetrain_enum = COALESCE(EXCLUDED.drivetrain_enum, te.drivetrain_enum), is_ground_intake = COALESCE(EXCLUDED.is_ground_intake, te.is_ground_intake), is_chute_intake = COALESCE(EXCLUDED.is_chute_intake, te.is_chute_intake), polish = COALESCE(EXCLUDED.polish, te.polish) RETURNING id, status").bind(form.team_key).bind(form.event_key).bind(form.width).bind(form.length).bind(form.is_short).bind(form.is_camera).bind(form.drivetrain).bind(form.is_ground_intake).bind(form.is_chute_intake).bind(form.polish).bind(form.scout_id).fetch_one(&mut *tx).await.map_err(ApiError::sqlx)?;
+
+    if let Some(old_value) = existing {
+        let new_value: Value =
+            sqlx::query_scalar("SELECT to_jsonb(t) FROM \"TeamEvents\" t WHERE t.id = $1")
+                .bind(submission.id)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(ApiError::sqlx)?;
+        record_revision(
+            &mut tx,
+            TEAM_EVENTS,
+            submission.id,
+            "update",
+            &editor_id,
+            old_value,
+            new_value,
+        )
+        .await
+        .map_err(ApiError::sqlx)?;
     }
+
+    tx.commit().await.map_err(ApiError::sqlx)?;
+
+    Ok(submission)
 }
 
-pub async fn submit_pit_data(state: &AppState, form: TeamEvent) -> impl IntoResponse {
-    let result = sqlx::query("INSERT INTO \"TeamEvents\" (team_key, event_key, width, length, is_short, is_camera, drivetrain_enum, is_ground_intake, is_chute_intake, polish, scout_id) VALUES ($1, $2, $3, $4, $5, $6, $7,$8, $9, $10, $11)").bind(form.team_key).bind(form.event_key).bind(form.width).bind(form.length).bind(form.is_short).bind(form.is_camera).bind(form.drivetrain).bind(form.is_ground_intake).bind(form.is_chute_intake).bind(form.polish).bind(form.scout_id).execute(&state.db.pool).await;
+#[derive(Debug, Serialize)]
+pub struct PitSubmissionResponse {
+    pub id: i64,
+    pub status: PitStatus,
+    /// How each photo went, the pit data is kept even when some of them fail
+    pub images: Vec<upload::UploadResult>,
+}
 
-    match result {
-        Ok(_) => return StatusCode::OK,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+/// Pit scouting form as a `data` field of `TeamEvent` JSON, with any other fields treated as photos of the robot
+#[axum::debug_handler]
+pub async fn submit_pit_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Json<PitSubmissionResponse>, (StatusCode, String)> {
+    let user = get_user_from_headers(&state.db, &headers).await?;
+
+    let mut form: Option<TeamEvent> = None;
+    let mut photos: Vec<(String, Bytes)> = vec![];
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return Err((StatusCode::BAD_REQUEST, err.to_string())),
+        };
+
+        let is_data = field.name() == Some("data");
+        let file = field
+            .file_name()
+            .map(|file| file.to_string())
+            .unwrap_or_else(|| photos.len().to_string());
+        let bytes = match field.bytes().await {
+            Ok(bytes) => bytes,
+            Err(err) => return Err((StatusCode::BAD_REQUEST, err.to_string())),
+        };
+
+        if is_data {
+            form = match serde_json::from_slice::<TeamEvent>(&bytes) {
+                Ok(form) => Some(form),
+                Err(err) => {
+                    return Err((StatusCode::BAD_REQUEST, format!("Invalid pit data: {err}")))
+                }
+            };
+        } else {
+            photos.push((file, bytes));
+        }
+    }
+
+    let mut form = match form {
+        Some(form) => form,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Missing pit data field".to_string(),
+            ))
+        }
+    };
+    form.scout_id = user.id.clone();
+
+    let keys = ScoutEventTeam {
+        event_key: form.event_key.clone(),
+        team_key: form.team_key.clone(),
+        scout_id: user.id,
+    };
+
+    let submission = submit_pit_data(&state.db, form).await?;
+
+    // The pit data is already committed, so a photo that fails is reported rather than failing the request
+    let mut images = vec![];
+    for (file, photo) in photos {
+        images.push(
+            match upload::save_image(
+                &state.db.pool,
+                state.image_store.as_ref(),
+                &state.image_workers,
+                &keys,
+                photo,
+                Some(submission.id),
+            )
+            .await
+            {
+                Ok(image) => upload::UploadResult {
+                    file,
+                    image: Some(image),
+                    error: None,
+                },
+                Err((_, err)) => {
+                    error!("Failed to save pit photo {}: {}", file, err);
+                    upload::UploadResult {
+                        file,
+                        image: None,
+                        error: Some(err),
+                    }
+                }
+            },
+        );
     }
+
+    info!(
+        "Pit data for {} submitted with {} of {} photos",
+        keys.team_key,
+        images
+            .iter()
+            .filter(|result| result.image.is_some())
+            .count(),
+        images.len()
+    );
+
+    Ok(Json(PitSubmissionResponse {
+        id: submission.id,
+        status: submission.status,
+        images,
+    }))
 }
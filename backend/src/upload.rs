@@ -1,110 +1,857 @@
-use crate::model;
+use crate::{error::ApiError, model, storage::ImageStore};
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     extract::{Multipart, Path, Query, State},
-    http::{Response, StatusCode},
+    http::{
+        header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY},
+        HeaderMap, Response, StatusCode,
+    },
     response::IntoResponse,
+    Json,
 };
-use serde::{de, Deserialize, Deserializer};
-use std::{
-    fmt,
-    fs::File,
-    io::{BufWriter, Cursor},
-    str::FromStr,
+use image::{
+    imageops::FilterType,
+    io::{Limits, Reader},
+    DynamicImage, ImageFormat, ImageOutputFormat,
 };
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use std::{fmt, io::Cursor, str::FromStr, sync::Arc};
+use tokio::sync::Semaphore;
+use tracing::{error, info};
 use uuid::Uuid;
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct ResizeOptions {
     #[serde(default, deserialize_with = "empty_string_as_none")]
     width: Option<u32>,
     height: Option<u32>,
     scale: Option<f32>,
+    #[serde(default)]
+    variant: Variant,
+    format: Option<OutputFormat>,
 }
 
+/// Sides on demand resizes are rounded down to, so the cache holds a bounded set of sizes per image
+const RESIZE_STEPS: [u32; 8] = [64, 128, 256, 320, 512, 1024, 1600, 2048];
+
+/// Scales are rounded down to a multiple of this many percent
+const SCALE_STEP: f32 = 5.0;
+
+impl ResizeOptions {
+    fn is_resize(&self) -> bool {
+        self.width.is_some() || self.height.is_some() || self.scale.is_some()
+    }
+
+    /// Snaps the requested size down to the fixed steps so the output is never larger than asked for,
+    /// sides below the smallest step are kept as is
+    fn bucketed(&self) -> ResizeOptions {
+        let side = |side: u32| {
+            RESIZE_STEPS
+                .into_iter()
+                .rev()
+                .find(|step| *step <= side)
+                .unwrap_or(side)
+        };
+        let sized = self.width.is_some() || self.height.is_some();
+
+        ResizeOptions {
+            width: self.width.map(side),
+            height: self.height.map(side),
+            // A width or height wins over the scale, out of range scales are left for `target_size` to reject
+            scale: self.scale.filter(|_| !sized).map(|scale| {
+                if (SCALE_STEP..=100.0).contains(&scale) {
+                    (scale / SCALE_STEP).floor() * SCALE_STEP
+                } else if scale > 0.0 && scale < SCALE_STEP {
+                    scale.floor().max(1.0)
+                } else {
+                    scale
+                }
+            }),
+            variant: self.variant,
+            format: self.format,
+        }
+    }
+}
+
+/// Whether an `If-None-Match` header lists the ETag, compared weakly as RFC 9110 asks for GET
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || strip_weak(tag) == strip_weak(etag))
+}
+
+/// Standard sizes generated at upload, so the strategy page can load thumbnails without resizing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    Thumb,
+    Medium,
+    #[default]
+    Full,
+}
+
+impl Variant {
+    const GENERATED: [Variant; 2] = [Variant::Thumb, Variant::Medium];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Variant::Thumb => "thumb",
+            Variant::Medium => "medium",
+            Variant::Full => "full",
+        }
+    }
+
+    /// Longest side of the variant, None keeps the original size
+    fn max_side(&self) -> Option<u32> {
+        match self {
+            Variant::Thumb => Some(320),
+            Variant::Medium => Some(1024),
+            Variant::Full => None,
+        }
+    }
+
+    fn key(&self, name: &str, full_key: &str) -> String {
+        match self {
+            Variant::Full => full_key.to_string(),
+            variant => format!(
+                "{name}_{}.{}",
+                variant.as_str(),
+                OutputFormat::from_key(full_key).extension()
+            ),
+        }
+    }
+
+    fn generate(&self, image: &DynamicImage) -> DynamicImage {
+        match self.max_side() {
+            Some(side) if image.width() > side || image.height() > side => {
+                image.resize(side, side, FilterType::Lanczos3)
+            }
+            _ => image.clone(),
+        }
+    }
+}
+
+/// Formats images are stored and served in. Every image is re-encoded from its pixels,
+/// so EXIF metadata such as the location a photo was taken never leaves the phone.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Webp,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "webp",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+        }
+    }
+
+    /// Format originals are stored in, from `IMAGE_STORE_FORMAT`
+    fn stored() -> Self {
+        match std::env::var("IMAGE_STORE_FORMAT").as_deref() {
+            Ok("webp") => OutputFormat::Webp,
+            Ok("png") => OutputFormat::Png,
+            _ => OutputFormat::Jpeg,
+        }
+    }
+
+    /// Images uploaded before originals were re-encoded are all PNGs
+    fn from_key(key: &str) -> Self {
+        match key.rsplit('.').next() {
+            Some("webp") => OutputFormat::Webp,
+            Some("jpg") => OutputFormat::Jpeg,
+            _ => OutputFormat::Png,
+        }
+    }
+
+    /// Picks what to serve from the `Accept` header by q-value. The stored format wins ties, so
+    /// browsers that list `image/webp` next to `image/*` still get it untouched. The WebP encoder
+    /// is lossless and ignores `IMAGE_QUALITY`, so it is only used when strictly preferred.
+    fn from_accept(accept: &str, stored: OutputFormat) -> Self {
+        if accept.trim().is_empty() {
+            return stored;
+        }
+
+        let ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|range| {
+                let mut params = range.split(';');
+                let media_type = params.next().unwrap_or_default().trim();
+                let q = params
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.trim().parse().ok())
+                    .unwrap_or(1.0);
+                (media_type, q)
+            })
+            .collect();
+        let range = |media_type: &str| {
+            ranges
+                .iter()
+                .find(|(range, _)| range.eq_ignore_ascii_case(media_type))
+                .map(|(_, q)| *q)
+        };
+
+        // The most specific range decides, so `image/webp;q=0` refuses WebP even next to `*/*`
+        let preference = |format: OutputFormat| {
+            range(format.content_type())
+                .or_else(|| range("image/*"))
+                .or_else(|| range("*/*"))
+                .unwrap_or(0.0)
+        };
+
+        [
+            stored,
+            OutputFormat::Webp,
+            OutputFormat::Jpeg,
+            OutputFormat::Png,
+        ]
+        .into_iter()
+        .map(|format| (format, preference(format)))
+        .filter(|(_, q)| *q > 0.0)
+        .reduce(|best, next| if next.1 > best.1 { next } else { best })
+        .map(|(format, _)| format)
+        .unwrap_or(stored)
+    }
+
+    fn encode(&self, image: &DynamicImage) -> Result<Vec<u8>, (StatusCode, String)> {
+        let mut buffer = Cursor::new(Vec::new());
+        let result = match self {
+            // The pure Rust WebP encoder is lossless, JPEG is the lossy option
+            OutputFormat::Webp => image.write_to(&mut buffer, ImageOutputFormat::WebP),
+            OutputFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_to(&mut buffer, ImageOutputFormat::Jpeg(quality())),
+            OutputFormat::Png => image.write_to(&mut buffer, ImageOutputFormat::Png),
+        };
+
+        match result {
+            Ok(_) => Ok(buffer.into_inner()),
+            Err(err) => {
+                error!("Failed to encode image as {:?}: {}", self, err);
+                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+            }
+        }
+    }
+}
+
+fn quality() -> u8 {
+    std::env::var("IMAGE_QUALITY")
+        .ok()
+        .and_then(|quality| quality.parse().ok())
+        .unwrap_or(80u8)
+        .clamp(1, 100)
+}
+
+/// Every key written to the store for an image, the original and each generated variant
+pub fn stored_keys(name: &str, storage_key: &str) -> Vec<String> {
+    std::iter::once(storage_key.to_string())
+        .chain(
+            Variant::GENERATED
+                .iter()
+                .map(|variant| variant.key(name, storage_key)),
+        )
+        .collect()
+}
+
+const CACHE_CONTROL_VALUE: &str = "public, max-age=31536000, immutable";
+
+const ALLOWED_FORMATS: [ImageFormat; 3] = [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP];
+
+fn max_dimension() -> u32 {
+    std::env::var("IMAGE_MAX_DIMENSION")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(8192)
+}
+
+fn max_pixels() -> u64 {
+    std::env::var("IMAGE_MAX_PIXELS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(40_000_000)
+}
+
+/// Decodes untrusted image bytes, rejecting unknown formats and images too large to decode safely
+pub fn decode_image(data: &[u8]) -> Result<DynamicImage, (StatusCode, String)> {
+    let bad_request = |err: String| (StatusCode::BAD_REQUEST, err);
+
+    let reader = Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|err| bad_request(err.to_string()))?;
+
+    match reader.format() {
+        Some(format) if ALLOWED_FORMATS.contains(&format) => {}
+        Some(format) => return Err(bad_request(format!("Unsupported image format {format:?}"))),
+        None => return Err(bad_request("Unrecognized image format".to_string())),
+    }
+
+    // Headers are checked before decoding so a tiny file can't claim a huge image
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|err| bad_request(format!("Invalid image: {err}")))?;
+    let max_dimension = max_dimension();
+    if width > max_dimension || height > max_dimension {
+        return Err(bad_request(format!(
+            "Image is {width}x{height}, max dimension is {max_dimension}"
+        )));
+    }
+    if width as u64 * height as u64 > max_pixels() {
+        return Err(bad_request(format!(
+            "Image is {width}x{height}, too many pixels"
+        )));
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_dimension);
+    limits.max_image_height = Some(max_dimension);
+    limits.max_alloc = Some(max_pixels() * 4);
+
+    let mut reader = Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|err| bad_request(err.to_string()))?;
+    reader.limits(limits);
+
+    let image = reader
+        .decode()
+        .map_err(|err| bad_request(format!("Invalid image: {err}")))?;
+
+    Ok(apply_orientation(image, exif_orientation(data)))
+}
+
+/// EXIF orientation of a photo, phones save pixels as the sensor read them and tag how to turn them upright
+fn exif_orientation(data: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+                .value
+                .get_uint(0)
+        })
+        .unwrap_or(1)
+}
+
+/// Turns the decoded pixels upright, the tag itself is lost when the image is re-encoded
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Decoding, resizing and encoding run on tokio's blocking pool, `IMAGE_WORKERS` at a time,
+/// so a rush of pit photos can't starve socket.io and the rest of the API
+#[derive(Clone)]
+pub struct ImageWorkers {
+    permits: Arc<Semaphore>,
+}
+
+impl ImageWorkers {
+    pub fn from_env() -> Self {
+        let workers = std::env::var("IMAGE_WORKERS")
+            .ok()
+            .and_then(|workers| workers.parse().ok())
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|cpus| cpus.get())
+                    .unwrap_or(2)
+            })
+            .max(1);
+
+        info!("Processing images with {} workers", workers);
+        ImageWorkers {
+            permits: Arc::new(Semaphore::new(workers)),
+        }
+    }
+
+    pub async fn run<T, F>(&self, work: F) -> Result<T, (StatusCode, String)>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, (StatusCode, String)> + Send + 'static,
+    {
+        // Turned away straight away when every worker is busy, waiting would hold the connection open
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                return Err((
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "Too many images are being processed, try again shortly".to_string(),
+                ))
+            }
+        };
+
+        match tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            work()
+        })
+        .await
+        {
+            Ok(result) => result,
+            Err(err) => {
+                error!("Image worker failed: {}", err);
+                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+            }
+        }
+    }
+}
+
+/// Everything stored for a new upload, built on an image worker
+struct ProcessedImage {
+    phash: i64,
+    /// Encoded original first, then each generated variant
+    files: Vec<(String, Vec<u8>)>,
+}
+
+fn process_upload(data: &[u8], name: &str) -> Result<ProcessedImage, (StatusCode, String)> {
+    let img = decode_image(data)?;
+
+    let format = OutputFormat::stored();
+    let key = format!("{name}.{}", format.extension());
+
+    let mut files = vec![(key.clone(), format.encode(&img)?)];
+    for variant in Variant::GENERATED {
+        files.push((
+            variant.key(name, &key),
+            format.encode(&variant.generate(&img))?,
+        ));
+    }
+
+    Ok(ProcessedImage {
+        phash: perceptual_hash(&img),
+        files,
+    })
+}
+
+async fn put_image(
+    store: &dyn ImageStore,
+    key: &str,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    match store.put(key, data.clone()).await {
+        Ok(_) => Ok(data),
+        Err(err) => {
+            error!("Failed to store image {}: {}", key, err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store image: {err}"),
+            ))
+        }
+    }
+}
+
+async fn get_image(
+    store: &dyn ImageStore,
+    key: &str,
+) -> Result<Option<Vec<u8>>, (StatusCode, String)> {
+    store.get(key).await.map_err(|err| {
+        error!("Failed to read image {}: {}", key, err);
+        (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    })
+}
+
+/// Photos whose perceptual hashes differ in at most this many bits are flagged as near duplicates
+const NEAR_DUPLICATE_DISTANCE: u32 = 8;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SavedImage {
+    pub name: String,
+    /// The exact same file was already uploaded for the team, so the existing image was returned
+    pub duplicate: bool,
+}
+
+/// Difference hash of the image, each bit is whether a pixel is brighter than its right neighbour
+/// in a 9x8 grayscale thumbnail. Resizing, recompression and small edits barely change it.
+fn perceptual_hash(image: &DynamicImage) -> i64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash: u64 = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+
+    hash as i64
+}
+
+fn hash_distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Finds an identical upload for the team, attaching it to the pit submission if it isn't already
+async fn find_duplicate(
+    db: &Pool<Postgres>,
+    keys: &model::ScoutEventTeam,
+    content_hash: &str,
+    team_event_id: Option<i64>,
+) -> Result<Option<String>, (StatusCode, String)> {
+    sqlx::query_scalar("UPDATE images SET team_event_id = COALESCE(team_event_id, $4) WHERE event_key = $1 AND team_key = $2 AND content_hash = $3 RETURNING name")
+        .bind(keys.event_key.clone())
+        .bind(keys.team_key.clone())
+        .bind(content_hash)
+        .bind(team_event_id)
+        .fetch_optional(db)
+        .await
+        .map_err(ApiError::sqlx)
+}
+
+async fn find_near_duplicate(
+    db: &Pool<Postgres>,
+    keys: &model::ScoutEventTeam,
+    phash: i64,
+) -> Result<Option<String>, (StatusCode, String)> {
+    let hashes: Vec<(String, i64)> = sqlx::query_as("SELECT name, phash FROM images WHERE event_key = $1 AND team_key = $2 AND phash IS NOT NULL ORDER BY created_at")
+        .bind(keys.event_key.clone())
+        .bind(keys.team_key.clone())
+        .fetch_all(db)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+    Ok(hashes
+        .into_iter()
+        .find(|(_, other)| hash_distance(phash, *other) <= NEAR_DUPLICATE_DISTANCE)
+        .map(|(name, _)| name))
+}
+
+/// Stores an uploaded image and records it. Uploading the same file twice for a team returns the first image.
+pub async fn save_image(
+    db: &Pool<Postgres>,
+    store: &dyn ImageStore,
+    workers: &ImageWorkers,
+    keys: &model::ScoutEventTeam,
+    data: Bytes,
+    team_event_id: Option<i64>,
+) -> Result<SavedImage, (StatusCode, String)> {
+    let image_url: String = std::env::var("IMAGE_URL").expect("IMAGE_URL is not set");
+
+    let hashed = data.clone();
+    let content_hash = workers
+        .run(move || Ok(format!("{:x}", Sha256::digest(&hashed))))
+        .await?;
+    if let Some(name) = find_duplicate(db, keys, &content_hash, team_event_id).await? {
+        info!("Image for {} is a duplicate of {}", keys.team_key, name);
+        return Ok(SavedImage {
+            name,
+            duplicate: true,
+        });
+    }
+
+    let name = Uuid::new_v4().to_string();
+    let processed = {
+        let name = name.clone();
+        workers.run(move || process_upload(&data, &name)).await?
+    };
+    let phash = processed.phash;
+    let near_duplicate_of = find_near_duplicate(db, keys, phash).await?;
+
+    let key = processed.files[0].0.clone();
+    for (file_key, file) in processed.files {
+        put_image(store, &file_key, file).await?;
+    }
+
+    let inserted = sqlx::query_scalar::<_, String>("INSERT INTO images (name, event_key, team_key, url, scout_id, team_event_id, storage_key, content_hash, phash, near_duplicate_of) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) ON CONFLICT (event_key, team_key, content_hash) WHERE content_hash IS NOT NULL DO NOTHING RETURNING name")
+        .bind(name.clone())
+        .bind(keys.event_key.clone())
+        .bind(keys.team_key.clone())
+        .bind(format!("{image_url}/submit/image/{name}"))
+        .bind(keys.scout_id.clone())
+        .bind(team_event_id)
+        .bind(key.clone())
+        .bind(content_hash.clone())
+        .bind(phash)
+        .bind(near_duplicate_of)
+        .fetch_optional(db)
+        .await;
+
+    match inserted {
+        Ok(Some(name)) => Ok(SavedImage {
+            name,
+            duplicate: false,
+        }),
+        // The same file was saved by a concurrent upload while this one was being stored
+        Ok(None) => {
+            for stored in stored_keys(&name, &key) {
+                if let Err(err) = store.delete(&stored).await {
+                    error!("Failed to delete duplicate image {}: {}", stored, err);
+                }
+            }
+
+            match find_duplicate(db, keys, &content_hash, team_event_id).await? {
+                Some(name) => Ok(SavedImage {
+                    name,
+                    duplicate: true,
+                }),
+                None => Err((
+                    StatusCode::CONFLICT,
+                    "Image was removed while uploading".to_string(),
+                )),
+            }
+        }
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadResult {
+    /// File name from the form, or its position when the browser didn't send one
+    pub file: String,
+    pub image: Option<SavedImage>,
+    pub error: Option<String>,
+}
+
+/// Saves every file in the form, reporting how each one went. Fails outright only when nothing was saved.
 pub async fn upload(
     State(state): State<model::AppState>,
     Query(keys): Query<model::ScoutEventTeam>,
     mut multipart: Multipart,
-) -> impl IntoResponse {
-    let mut tasks = Vec::new();
-    let _image_dir: String = std::env::var("IMAGE_DIR").expect("IMAGE_DIR is not set");
-
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let data = field.bytes().await.unwrap();
-
-        let new_keys = keys.clone();
-        let new_db = state.db.pool.clone();
-
-        let task = tokio::spawn(async move {
-            let image_url: String = std::env::var("IMAGE_URL").expect("IMAGE_URL is not set");
-            let name = Uuid::new_v4().to_string();
-            let img = image::load_from_memory(&data).unwrap();
-
-            let path = format!("{image_url}/{name}.png");
-            let file = File::create(&path).unwrap();
-            let mut writer = BufWriter::new(file);
-            img.write_to(&mut writer, image::ImageOutputFormat::Png)
-                .unwrap();
-            let _ = sqlx::query("INSERT INTO images (name, event_key, team_key, url, scout_id) VALUES ($1, $2, $3, $4, $5) IF EXISTS THEN DO NOTHING")
-                .bind(name)
-                .bind(new_keys.event_key)
-                .bind(new_keys.team_key)
-                .bind(path) // FIXME: Make this the correct url
-                .bind(new_keys.scout_id)
-                .execute(&new_db)
-                .await;
-        });
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut files = Vec::new();
 
-        tasks.push(task);
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) => {
+                let file = field
+                    .file_name()
+                    .map(|file| file.to_string())
+                    .unwrap_or_else(|| files.len().to_string());
+                match field.bytes().await {
+                    Ok(data) => files.push((file, data)),
+                    Err(err) => return Err((StatusCode::BAD_REQUEST, err.to_string())),
+                }
+            }
+            Ok(None) => break,
+            Err(err) => return Err((StatusCode::BAD_REQUEST, err.to_string())),
+        };
     }
 
-    for task in tasks {
-        task.await.unwrap();
+    // One file at a time, so a big form doesn't take every image worker and turn its own files away
+    let mut results = Vec::new();
+    let mut failure = None;
+    for (file, data) in files {
+        let result = save_image(
+            &state.db.pool,
+            state.image_store.as_ref(),
+            &state.image_workers,
+            &keys,
+            data,
+            None,
+        )
+        .await;
+
+        results.push(match result {
+            Ok(image) => UploadResult {
+                file,
+                image: Some(image),
+                error: None,
+            },
+            Err((status, err)) => {
+                error!("Failed to save uploaded image {}: {}", file, err);
+                failure = Some((status, err.clone()));
+                UploadResult {
+                    file,
+                    image: None,
+                    error: Some(err),
+                }
+            }
+        });
     }
 
-    Response::builder()
-        .status(StatusCode::CREATED)
-        .body(Body::empty())
-        .unwrap()
+    match failure {
+        Some(err) if results.iter().all(|result| result.image.is_none()) => Err(err),
+        _ => Ok((StatusCode::CREATED, Json(results))),
+    }
 }
 
-pub async fn image(
-    State(_state): State<model::AppState>,
-    Path(image): Path<String>,
-    options: Query<ResizeOptions>,
-) -> impl IntoResponse {
-    let image_dir: String = std::env::var("IMAGE_DIR").expect("IMAGE_DIR is not set");
-
-    let image = image::open(format!("{image_dir}/{image}")).unwrap();
+/// Requested size for on demand variants, keeping the aspect ratio when only one side is given
+fn target_size(
+    image: &DynamicImage,
+    options: &ResizeOptions,
+) -> Result<(u32, u32), (StatusCode, String)> {
+    let scaled = |side: u32, to: u32, from: u32| (side as u64 * to as u64 / from as u64) as u32;
 
-    let image = match (options.width, options.height, options.scale) {
+    let (width, height) = match (options.width, options.height, options.scale) {
+        // Fit inside the box rather than stretching to it
         (Some(width), Some(height), _) => {
-            image::imageops::resize(&image, width, height, image::imageops::FilterType::Nearest)
+            let by_width = scaled(image.height(), width, image.width());
+            if by_width <= height {
+                (width, by_width)
+            } else {
+                (scaled(image.width(), height, image.height()), height)
+            }
+        }
+        (Some(width), None, _) => (width, scaled(image.height(), width, image.width())),
+        (None, Some(height), _) => (scaled(image.width(), height, image.height()), height),
+        (None, None, Some(scale)) if scale <= 0.0 || scale > 100.0 => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Scale must be between 0 and 100".to_string(),
+            ))
         }
         (None, None, Some(scale)) => {
             let scale = scale / 100.0;
-            image::imageops::resize(
-                &image,
+            (
                 (scale * image.width() as f32) as u32,
                 (scale * image.height() as f32) as u32,
-                image::imageops::FilterType::Nearest,
             )
         }
-        _ => image.to_rgba8(),
+        (None, None, None) => (image.width(), image.height()),
     };
 
-    let mut buffer = Cursor::new(Vec::new());
-    image
-        .write_to(&mut buffer, image::ImageOutputFormat::Png)
-        .unwrap();
+    let max_dimension = max_dimension();
+    if width > max_dimension || height > max_dimension {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Max dimension is {max_dimension}"),
+        ));
+    }
+
+    Ok((width.max(1), height.max(1)))
+}
 
-    let body = Body::from(buffer.into_inner());
+pub async fn image(
+    State(state): State<model::AppState>,
+    Path(image): Path<String>,
+    options: Query<ResizeOptions>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    // Older links include the extension, only the UUID is used for the lookup
+    let name = image.split('.').next().unwrap_or(&image);
+    let name = match Uuid::parse_str(name) {
+        Ok(name) => name.to_string(),
+        Err(_) => return Err((StatusCode::BAD_REQUEST, "Invalid image id".to_string())),
+    };
 
-    Response::builder()
-        .header("Content-Type", "image/png")
-        .body(body)
-        .unwrap()
+    let full_key: String =
+        match sqlx::query_scalar("SELECT storage_key FROM images WHERE name = $1")
+            .bind(name.clone())
+            .fetch_optional(&state.db.pool)
+            .await
+        {
+            Ok(Some(key)) => key,
+            Ok(None) => return Err((StatusCode::NOT_FOUND, format!("No image {name}"))),
+            Err(err) => return Err(ApiError::sqlx(err)),
+        };
+
+    let options = options.bucketed();
+    let is_resize = options.is_resize();
+    let variant_id = match is_resize {
+        true => format!(
+            "{}x{}s{}",
+            options.width.unwrap_or(0),
+            options.height.unwrap_or(0),
+            options.scale.unwrap_or(0.0)
+        ),
+        false => options.variant.as_str().to_string(),
+    };
+
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or_default();
+    let stored_format = OutputFormat::from_key(&full_key);
+    let format = options
+        .format
+        .unwrap_or_else(|| OutputFormat::from_accept(accept, stored_format));
+
+    // Stored images never change, so the name, size and format are enough to identify the response
+    let etag = format!("\"{name}-{variant_id}.{}\"", format.extension());
+    if headers
+        .get(IF_NONE_MATCH)
+        .and_then(|tags| tags.to_str().ok())
+        .is_some_and(|tags| etag_matches(tags, &etag))
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(ETAG, etag)
+            .header(VARY, "Accept")
+            .header(CACHE_CONTROL, CACHE_CONTROL_VALUE)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    // Stored variants are served as is, anything resized or in another format goes through the cache
+    let is_stored = !is_resize && format == stored_format;
+    let (store, key): (&dyn ImageStore, String) = match is_stored {
+        true => (
+            state.image_store.as_ref(),
+            options.variant.key(&name, &full_key),
+        ),
+        false => (
+            state.image_cache.as_ref() as &dyn ImageStore,
+            format!("{name}/{variant_id}.{}", format.extension()),
+        ),
+    };
+
+    let data = match get_image(store, &key).await? {
+        Some(data) => data,
+        None => {
+            // Cache miss, or an image uploaded before variants were generated
+            let original = match get_image(state.image_store.as_ref(), &full_key).await? {
+                Some(data) => data,
+                None => {
+                    error!("Image {} is recorded but missing from the store", name);
+                    return Err((StatusCode::NOT_FOUND, format!("No image {name}")));
+                }
+            };
+
+            let options = options.clone();
+            let image_name = name.clone();
+            let resized = state
+                .image_workers
+                .run(move || {
+                    let original = match decode_image(&original) {
+                        Ok(image) => image,
+                        Err((_, err)) => {
+                            error!("Stored image {} is unreadable: {}", image_name, err);
+                            return Err((StatusCode::INTERNAL_SERVER_ERROR, err));
+                        }
+                    };
+
+                    let resized = match is_resize {
+                        true => {
+                            let (width, height) = target_size(&original, &options)?;
+                            original.resize_exact(width, height, FilterType::Lanczos3)
+                        }
+                        false => options.variant.generate(&original),
+                    };
+
+                    format.encode(&resized)
+                })
+                .await?;
+
+            put_image(store, &key, resized).await?
+        }
+    };
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, format.content_type())
+        .header(ETAG, etag)
+        .header(VARY, "Accept")
+        .header(CACHE_CONTROL, CACHE_CONTROL_VALUE)
+        .body(Body::from(data))
+        .unwrap())
 }
 
 // Serde deserialization decorator to map empty Strings to None
@@ -119,3 +866,175 @@ where
         Some(s) => FromStr::from_str(s).map_err(de::Error::custom).map(Some),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    #[test]
+    fn accept_prefers_stored_format_for_wildcards() {
+        assert_eq!(
+            OutputFormat::from_accept("", OutputFormat::Jpeg),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::from_accept("*/*", OutputFormat::Jpeg),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::from_accept("image/*;q=0.8", OutputFormat::Png),
+            OutputFormat::Png
+        );
+    }
+
+    #[test]
+    fn accept_keeps_stored_format_unless_another_is_preferred() {
+        // Chromium and Firefox list WebP by name, but accept JPEG just as much
+        assert_eq!(
+            OutputFormat::from_accept(
+                "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8",
+                OutputFormat::Jpeg
+            ),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::from_accept("image/avif,image/webp,*/*", OutputFormat::Jpeg),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::from_accept("image/webp, image/*;q=0.8", OutputFormat::Jpeg),
+            OutputFormat::Webp
+        );
+        assert_eq!(
+            OutputFormat::from_accept("image/webp;q=0.5, image/jpeg", OutputFormat::Png),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::from_accept("image/webp;q=0.5, */*", OutputFormat::Jpeg),
+            OutputFormat::Jpeg
+        );
+    }
+
+    #[test]
+    fn accept_q_zero_refuses_a_type() {
+        assert_eq!(
+            OutputFormat::from_accept("image/webp;q=0, */*", OutputFormat::Webp),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::from_accept("image/webp; q=0", OutputFormat::Jpeg),
+            OutputFormat::Jpeg
+        );
+    }
+
+    fn resize(width: Option<u32>, height: Option<u32>, scale: Option<f32>) -> ResizeOptions {
+        ResizeOptions {
+            width,
+            height,
+            scale,
+            variant: Variant::Full,
+            format: None,
+        }
+    }
+
+    #[test]
+    fn resizes_snap_down_to_fixed_steps() {
+        let bucketed = resize(Some(301), Some(9000), Some(50.0)).bucketed();
+        assert_eq!(bucketed.width, Some(256));
+        assert_eq!(bucketed.height, Some(2048));
+        assert_eq!(bucketed.scale, None);
+
+        assert_eq!(resize(Some(100), None, None).bucketed().width, Some(64));
+        assert_eq!(resize(Some(40), None, None).bucketed().width, Some(40));
+        assert_eq!(resize(None, None, Some(33.3)).bucketed().scale, Some(30.0));
+        assert_eq!(resize(None, None, Some(3.7)).bucketed().scale, Some(3.0));
+        // Left as is so the request is still rejected
+        assert_eq!(
+            resize(None, None, Some(500.0)).bucketed().scale,
+            Some(500.0)
+        );
+    }
+
+    #[test]
+    fn resizes_keep_the_aspect_ratio() {
+        let image = DynamicImage::new_rgb8(300, 200);
+
+        let boxed = resize(Some(300), Some(200), None).bucketed();
+        assert_eq!(target_size(&image, &boxed).unwrap(), (192, 128));
+
+        let wide = resize(Some(300), Some(10), None).bucketed();
+        assert_eq!(target_size(&image, &wide).unwrap(), (15, 10));
+
+        let (width, height) =
+            target_size(&image, &resize(Some(100), None, None).bucketed()).unwrap();
+        assert_eq!((width, height), (64, 42));
+        assert!((width as f32 / height as f32 - 1.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let etag = "\"abc-full.jpg\"";
+        assert!(etag_matches("\"abc-full.jpg\"", etag));
+        assert!(etag_matches("W/\"abc-full.jpg\"", etag));
+        assert!(etag_matches("\"other\", W/\"abc-full.jpg\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"abc-thumb.jpg\"", etag));
+    }
+
+    /// A 2x1 image, red on the left and blue on the right
+    fn two_pixels() -> DynamicImage {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        image.put_pixel(1, 0, Rgba([0, 0, 255, 255]));
+        DynamicImage::ImageRgba8(image)
+    }
+
+    #[test]
+    fn orientation_turns_pixels_upright() {
+        let red = Rgba([255, 0, 0, 255]);
+
+        assert_eq!(apply_orientation(two_pixels(), 1).get_pixel(0, 0), red);
+        assert_eq!(apply_orientation(two_pixels(), 2).get_pixel(1, 0), red);
+        assert_eq!(apply_orientation(two_pixels(), 3).get_pixel(1, 0), red);
+
+        // Rotated a quarter turn clockwise, the left pixel ends up on top
+        let rotated = apply_orientation(two_pixels(), 6);
+        assert_eq!(rotated.dimensions(), (1, 2));
+        assert_eq!(rotated.get_pixel(0, 0), red);
+
+        let rotated = apply_orientation(two_pixels(), 8);
+        assert_eq!(rotated.dimensions(), (1, 2));
+        assert_eq!(rotated.get_pixel(0, 1), red);
+    }
+
+    #[test]
+    fn exif_orientation_is_applied_on_decode() {
+        let mut jpeg = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(two_pixels().to_rgb8())
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let jpeg = jpeg.into_inner();
+
+        // APP1 segment holding a little endian TIFF header and one IFD entry, Orientation = 6
+        let mut app1 = vec![0xFF, 0xE1, 0x00, 0x22];
+        app1.extend_from_slice(b"Exif\0\0II*\0\x08\0\0\0\x01\0");
+        app1.extend_from_slice(&[0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        app1.extend_from_slice(&[0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let photo = [&jpeg[..2], &app1, &jpeg[2..]].concat();
+
+        assert_eq!(exif_orientation(&photo), 6);
+        assert_eq!(decode_image(&photo).unwrap().dimensions(), (1, 2));
+    }
+
+    #[test]
+    fn missing_exif_keeps_orientation() {
+        let mut png = Cursor::new(Vec::new());
+        two_pixels()
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+
+        assert_eq!(exif_orientation(png.get_ref()), 1);
+        assert_eq!(decode_image(png.get_ref()).unwrap().dimensions(), (2, 1));
+    }
+}
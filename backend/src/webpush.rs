@@ -1,62 +1,552 @@
-use crate::admin::get_user_helper;
-use crate::{
-    error,
-    model::{AppState, Db},
-};
+use crate::admin::get_user_from_headers;
+use crate::error::ApiError;
+use crate::model::{AppState, Db, User};
 use axum::{
+    body::Body,
     extract::{Json, State},
     response::IntoResponse,
 };
 use base64ct::{Base64UrlUnpadded, Encoding};
-use http::{HeaderMap, Uri};
+use futures::{future::join_all, StreamExt};
+use http::{header::RETRY_AFTER, HeaderMap, HeaderValue, Request, Uri};
 use hyper::StatusCode;
-use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use hyper_tls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tracing::info;
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, info};
 use web_push_native::{
-    jwt_simple::algorithms::ES256KeyPair, p256::elliptic_curve::PublicKey, Auth, WebPushBuilder,
+    jwt_simple::algorithms::{ECDSAP256PublicKeyLike, ES256KeyPair},
+    p256::elliptic_curve::PublicKey,
+    Auth, WebPushBuilder,
 };
 
-pub async fn send_web_push(db: &Db, token: String) -> Result<(), (StatusCode, String)> {
-    // THis is not being called
-    info!("Send webpush called");
-    let user = get_user_helper(db, token).await?;
+/// Notifications sent to scouts, the message itself is included as `data` for the service worker
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PushMessage {
+    Assignment {
+        match_key: String,
+        team_key: String,
+        color: String,
+    },
+    ShiftStart {
+        event_key: String,
+        starts_at: chrono::DateTime<chrono::Utc>,
+    },
+    MatchStarting {
+        match_key: String,
+        team_key: String,
+        color: String,
+    },
+    Broadcast {
+        id: String,
+        title: String,
+        body: String,
+    },
+}
+
+// frc1540 -> 1540
+fn team_number(team_key: &str) -> &str {
+    team_key.strip_prefix("frc").unwrap_or(team_key)
+}
+
+impl PushMessage {
+    fn title(&self) -> String {
+        match self {
+            PushMessage::Assignment { match_key, .. } => format!("Assigned to {match_key}"),
+            PushMessage::ShiftStart { .. } => "Your shift is starting".to_string(),
+            PushMessage::MatchStarting { match_key, .. } => format!("{match_key} is starting"),
+            PushMessage::Broadcast { title, .. } => title.clone(),
+        }
+    }
+
+    fn body(&self) -> String {
+        match self {
+            PushMessage::Assignment {
+                team_key, color, ..
+            }
+            | PushMessage::MatchStarting {
+                team_key, color, ..
+            } => format!("Scout {} on the {} alliance", team_number(team_key), color),
+            PushMessage::ShiftStart { starts_at, .. } => format!(
+                "Time to scout! Your shift starts at {}",
+                starts_at.with_timezone(&chrono::Local).format("%-I:%M %p")
+            ),
+            PushMessage::Broadcast { body, .. } => body.clone(),
+        }
+    }
+
+    /// Seconds the push service keeps trying to reach the device, the message is useless after that
+    fn ttl(&self) -> u32 {
+        match self {
+            PushMessage::Assignment { .. } => 15 * 60,
+            PushMessage::ShiftStart { .. } => 30 * 60,
+            PushMessage::MatchStarting { .. } => 5 * 60,
+            PushMessage::Broadcast { .. } => 10 * 60,
+        }
+    }
 
-    let VAPID_PRIVATE = std::env::var("VAPID_PRIVATE").expect("VAPID_PRIVATE not set");
-    let ENDPOINT = user.endpoint.clone().unwrap();
-    let P256DH = user.p256dh.clone().unwrap();
-    let AUTH = user.auth.clone().unwrap();
+    fn urgency(&self) -> &'static str {
+        match self {
+            PushMessage::ShiftStart { .. } => "normal",
+            PushMessage::Assignment { .. }
+            | PushMessage::MatchStarting { .. }
+            | PushMessage::Broadcast { .. } => "high",
+        }
+    }
 
-    let vapid = Base64UrlUnpadded::decode_vec(&VAPID_PRIVATE).unwrap();
-    let endpoint: Uri = ENDPOINT.parse().unwrap();
-    let P256DH = Base64UrlUnpadded::decode_vec(&P256DH).unwrap();
-    let AUTH = Base64UrlUnpadded::decode_vec(&AUTH).unwrap();
+    /// Undelivered messages with the same topic are replaced, so an offline scout only gets the latest
+    fn topic(&self) -> &'static str {
+        match self {
+            PushMessage::Assignment { .. } => "assignment",
+            PushMessage::ShiftStart { .. } => "shift-start",
+            PushMessage::MatchStarting { .. } => "match-starting",
+            PushMessage::Broadcast { .. } => "broadcast",
+        }
+    }
 
-    let key_pair = ES256KeyPair::from_bytes(&vapid).unwrap();
-    let ua_public = PublicKey::from_sec1_bytes(&P256DH).unwrap();
-    let ua_auth = Auth::clone_from_slice(&AUTH);
+    fn kind(&self) -> NotificationKind {
+        match self {
+            PushMessage::Assignment { .. } | PushMessage::MatchStarting { .. } => {
+                NotificationKind::Assignment
+            }
+            PushMessage::ShiftStart { .. } => NotificationKind::ShiftReminder,
+            PushMessage::Broadcast { .. } => NotificationKind::Broadcast,
+        }
+    }
 
-    let message = json!({
-      "title": "Scouting Notification",
-      "body": "Time to scout!"
-    });
+    fn payload(&self) -> Value {
+        json!({
+            "title": self.title(),
+            "body": self.body(),
+            "data": self,
+        })
+    }
+}
 
-    let builder = WebPushBuilder::new(endpoint, ua_public, ua_auth)
-        .with_vapid(&key_pair, "colburna@team1540.catlin.edu")
-        .build(message.to_string())
-        .unwrap()
-        .map(axum::body::Body::from);
+/// Where a browser receives notifications, from the browser's `PushSubscription`
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Subscription {
+    pub user_id: String,
+    pub endpoint: String,
+    pub p256dh: String,
+    pub auth: String,
+}
 
-    info!("builder: {:?}", builder);
+/// Kinds of notification scouts can turn off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotificationKind {
+    Assignment,
+    ShiftReminder,
+    Broadcast,
+}
 
-    let https = hyper_tls::HttpsConnector::new();
-    let client = Client::builder(TokioExecutor::new()).build(https);
-    info!("Client sent push notification: {:?}", client);
-    let test = client.request(builder).await.unwrap();
-    info!("test: {:?}", test);
+impl User {
+    pub fn wants(&self, kind: NotificationKind) -> bool {
+        self.is_notify
+            && match kind {
+                NotificationKind::Assignment => self.notify_assignment,
+                NotificationKind::ShiftReminder => self.notify_shift,
+                NotificationKind::Broadcast => self.notify_broadcast,
+            }
+    }
+}
 
-    Ok(())
+struct PushJob {
+    subscription: Subscription,
+    message: PushMessage,
+    /// Attempts made so far, retries go back in the queue
+    attempts: u32,
+    /// Told whether the push service accepted the message
+    report: Option<oneshot::Sender<bool>>,
+}
+
+/// How sending a notification to one user went
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PushStatus {
+    /// At least one of their devices accepted it
+    Delivered,
+    Failed,
+    NoDevices,
+    OptedOut,
+}
+
+/// Notifications waiting to be sent before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+/// Notifications being delivered at once
+const CONCURRENCY: usize = 16;
+const MAX_ATTEMPTS: u32 = 5;
+/// Longest wait before a retry, however long the push service asks for
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Queues notifications for a background worker so sending never holds up a request or the socket
+#[derive(Clone)]
+pub struct PushService {
+    db: Db,
+    jobs: mpsc::Sender<PushJob>,
+    public_key: String,
+}
+
+struct Delivery {
+    db: Db,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    key_pair: ES256KeyPair,
+    subject: String,
+    /// Weak so the worker stops once the service is dropped
+    retries: mpsc::WeakSender<PushJob>,
+}
+
+enum Outcome {
+    Delivered,
+    /// The browser unsubscribed or the subscription expired
+    Gone,
+    Retry(Duration),
+    Failed(String),
+}
+
+/// The public key browsers subscribe with, base64url encoded like `VAPID_PUBLIC`
+pub fn public_key_of(key_pair: &ES256KeyPair) -> String {
+    Base64UrlUnpadded::encode_string(&key_pair.public_key().public_key().to_bytes_uncompressed())
+}
+
+/// Reads the VAPID key pair, failing if `VAPID_PUBLIC` isn't the public half of `VAPID_PRIVATE`.
+/// Browsers would otherwise subscribe with a key the push services then reject every message for.
+pub fn load_vapid_keys() -> anyhow::Result<(ES256KeyPair, String)> {
+    let private_key = std::env::var("VAPID_PRIVATE").map_err(|_| {
+        anyhow::anyhow!("VAPID_PRIVATE not set, generate keys with `backend vapid-keygen`")
+    })?;
+    let public_key = std::env::var("VAPID_PUBLIC").map_err(|_| {
+        anyhow::anyhow!("VAPID_PUBLIC not set, generate keys with `backend vapid-keygen`")
+    })?;
+
+    let private_key = Base64UrlUnpadded::decode_vec(&private_key)
+        .map_err(|err| anyhow::anyhow!("Invalid VAPID_PRIVATE: {err}"))?;
+    let key_pair = ES256KeyPair::from_bytes(&private_key)?;
+
+    if public_key_of(&key_pair) != public_key {
+        return Err(anyhow::anyhow!(
+            "VAPID_PUBLIC doesn't match VAPID_PRIVATE, set both from the same `backend vapid-keygen` run"
+        ));
+    }
+
+    Ok((key_pair, public_key))
+}
+
+impl PushService {
+    /// Starts the delivery worker, `VAPID_PRIVATE`, `VAPID_PUBLIC` and `VAPID_SUBJECT` must be set
+    pub fn start(db: Db) -> Self {
+        let subject = std::env::var("VAPID_SUBJECT").expect("VAPID_SUBJECT not set");
+        let (key_pair, _) = load_vapid_keys().unwrap_or_else(|err| panic!("{err}"));
+
+        PushService::with_key_pair(db, key_pair, subject)
+    }
+
+    fn with_key_pair(db: Db, key_pair: ES256KeyPair, subject: String) -> Self {
+        let public_key = public_key_of(&key_pair);
+        let (jobs, queue) = mpsc::channel(QUEUE_SIZE);
+
+        let delivery = Arc::new(Delivery {
+            db: db.clone(),
+            client: Client::builder(TokioExecutor::new()).build(HttpsConnector::new()),
+            key_pair,
+            subject,
+            retries: jobs.downgrade(),
+        });
+
+        tokio::spawn(async move {
+            ReceiverStream::new(queue)
+                .for_each_concurrent(CONCURRENCY, |job: PushJob| {
+                    let delivery = delivery.clone();
+                    async move { delivery.deliver(job).await }
+                })
+                .await
+        });
+
+        PushService {
+            db,
+            jobs,
+            public_key,
+        }
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// Flags subscriptions made with an earlier VAPID key, browsers re-register them on their next visit
+    pub async fn flag_stale_subscriptions(&self) -> Result<(), sqlx::Error> {
+        // Subscriptions from before keys were tracked were made with the key in use at the time
+        sqlx::query("UPDATE \"PushSubscriptions\" SET vapid_key = $1 WHERE vapid_key IS NULL")
+            .bind(&self.public_key)
+            .execute(&self.db.pool)
+            .await?;
+
+        let flagged = sqlx::query("UPDATE \"PushSubscriptions\" SET needs_reregister = TRUE WHERE vapid_key <> $1 AND NOT needs_reregister")
+            .bind(&self.public_key)
+            .execute(&self.db.pool)
+            .await?
+            .rows_affected();
+
+        if flagged > 0 {
+            info!(
+                "VAPID key changed, {} subscriptions need to re-register",
+                flagged
+            );
+        }
+
+        Ok(())
+    }
+
+    fn queue(&self, job: PushJob) {
+        if let Err(err) = self.jobs.try_send(job) {
+            error!(
+                "Dropped push notification, queue is full or closed: {}",
+                err
+            );
+        }
+    }
+
+    /// Sends to every device the user subscribed from, unless they turned this kind of notification off
+    pub fn notify_user(&self, user: User, message: PushMessage) {
+        let push = self.clone();
+        tokio::spawn(async move {
+            if !user.wants(message.kind()) {
+                return;
+            }
+
+            match push.subscriptions(&user).await {
+                Ok(subscriptions) => {
+                    for subscription in subscriptions {
+                        push.queue(PushJob {
+                            subscription,
+                            message: message.clone(),
+                            attempts: 0,
+                            report: None,
+                        });
+                    }
+                }
+                Err(err) => error!("Failed to look up subscriptions for {}: {}", user.name, err),
+            }
+        });
+    }
+
+    /// Like `notify_user`, but waits to hear back from the push services
+    pub async fn deliver_to_user(&self, user: &User, message: PushMessage) -> PushStatus {
+        if !user.wants(message.kind()) {
+            return PushStatus::OptedOut;
+        }
+
+        let subscriptions = match self.subscriptions(user).await {
+            Ok(subscriptions) if subscriptions.is_empty() => return PushStatus::NoDevices,
+            Ok(subscriptions) => subscriptions,
+            Err(err) => {
+                error!("Failed to look up subscriptions for {}: {}", user.name, err);
+                return PushStatus::Failed;
+            }
+        };
+
+        let mut reports = Vec::new();
+        for subscription in subscriptions {
+            let (report, delivered) = oneshot::channel();
+            self.queue(PushJob {
+                subscription,
+                message: message.clone(),
+                attempts: 0,
+                report: Some(report),
+            });
+            reports.push(delivered);
+        }
+
+        // A report is dropped without an answer when the job never made it into the queue
+        match join_all(reports)
+            .await
+            .into_iter()
+            .any(|delivered| delivered.unwrap_or(false))
+        {
+            true => PushStatus::Delivered,
+            false => PushStatus::Failed,
+        }
+    }
+
+    async fn subscriptions(&self, user: &User) -> Result<Vec<Subscription>, sqlx::Error> {
+        sqlx::query_as::<_, Subscription>(
+            "SELECT * FROM \"PushSubscriptions\" WHERE user_id = $1 AND NOT needs_reregister",
+        )
+        .bind(&user.id)
+        .fetch_all(&self.db.pool)
+        .await
+    }
+
+    /// Scouts on the socket are known by `Users.id`, so they are looked up in the background
+    pub fn notify_scout(&self, scout_id: String, message: PushMessage) {
+        let push = self.clone();
+        tokio::spawn(async move {
+            match sqlx::query_as::<_, User>("SELECT * FROM \"Users\" WHERE id = $1")
+                .bind(&scout_id)
+                .fetch_optional(&push.db.pool)
+                .await
+            {
+                Ok(Some(user)) => push.notify_user(user, message),
+                Ok(None) => info!("No user {} to notify", scout_id),
+                Err(err) => error!("Failed to look up {} to notify: {}", scout_id, err),
+            }
+        });
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(2u64.saturating_pow(attempt)).min(MAX_BACKOFF)
+}
+
+/// How long the push service asked us to wait, capped so one response can't park a message for hours
+fn retry_after(headers: &HeaderMap, attempt: u32) -> Duration {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(backoff(attempt))
+        .min(MAX_BACKOFF)
+}
+
+impl Delivery {
+    fn build(
+        &self,
+        subscription: &Subscription,
+        message: &PushMessage,
+    ) -> anyhow::Result<Request<Body>> {
+        let endpoint: Uri = subscription.endpoint.parse()?;
+        let p256dh = Base64UrlUnpadded::decode_vec(&subscription.p256dh)
+            .map_err(|err| anyhow::anyhow!("Invalid p256dh: {err}"))?;
+        let auth = Base64UrlUnpadded::decode_vec(&subscription.auth)
+            .map_err(|err| anyhow::anyhow!("Invalid auth: {err}"))?;
+        if auth.len() != 16 {
+            return Err(anyhow::anyhow!("Invalid auth secret length {}", auth.len()));
+        }
+
+        let ua_public = PublicKey::from_sec1_bytes(&p256dh)
+            .map_err(|err| anyhow::anyhow!("Invalid p256dh: {err}"))?;
+        let ua_auth = Auth::clone_from_slice(&auth);
+
+        let mut request = WebPushBuilder::new(endpoint, ua_public, ua_auth)
+            .with_vapid(&self.key_pair, &self.subject)
+            .build(message.payload().to_string())
+            .map_err(|err| anyhow::anyhow!("Failed to build push message: {err}"))?
+            .map(Body::from);
+
+        let headers = request.headers_mut();
+        headers.insert("TTL", HeaderValue::from(message.ttl()));
+        headers.insert("Urgency", HeaderValue::from_static(message.urgency()));
+        headers.insert("Topic", HeaderValue::from_static(message.topic()));
+
+        Ok(request)
+    }
+
+    async fn attempt(
+        &self,
+        subscription: &Subscription,
+        message: &PushMessage,
+        attempt: u32,
+    ) -> Outcome {
+        let request = match self.build(subscription, message) {
+            Ok(request) => request,
+            Err(err) => return Outcome::Failed(err.to_string()),
+        };
+
+        let response = match self.client.request(request).await {
+            Ok(response) => response,
+            Err(err) => {
+                error!("Failed to reach push service: {}", err);
+                return Outcome::Retry(backoff(attempt));
+            }
+        };
+
+        match response.status() {
+            status if status.is_success() => Outcome::Delivered,
+            StatusCode::NOT_FOUND | StatusCode::GONE => Outcome::Gone,
+            status if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() => {
+                Outcome::Retry(retry_after(response.headers(), attempt))
+            }
+            status => Outcome::Failed(format!("Push service returned {status}")),
+        }
+    }
+
+    /// Makes the job's next attempt and reports whether the push service accepted it
+    async fn deliver(&self, mut job: PushJob) {
+        job.attempts += 1;
+        let user_id = &job.subscription.user_id;
+
+        let delivered = match self
+            .attempt(&job.subscription, &job.message, job.attempts)
+            .await
+        {
+            Outcome::Delivered => {
+                info!("Sent push notification to {}", user_id);
+                true
+            }
+            Outcome::Gone => {
+                info!("Push subscription for {} is gone, removing it", user_id);
+                self.remove(&job.subscription).await;
+                false
+            }
+            Outcome::Failed(err) => {
+                error!("Failed to send push notification to {}: {}", user_id, err);
+                false
+            }
+            Outcome::Retry(delay) if job.attempts < MAX_ATTEMPTS => {
+                self.retry(job, delay);
+                return;
+            }
+            Outcome::Retry(_) => {
+                error!(
+                    "Gave up on push notification to {} after {} attempts",
+                    user_id, MAX_ATTEMPTS
+                );
+                false
+            }
+        };
+
+        if let Some(report) = job.report {
+            let _ = report.send(delivered);
+        }
+    }
+
+    /// Queues the job again after the delay, waiting outside the worker so it keeps delivering
+    fn retry(&self, job: PushJob, delay: Duration) {
+        let retries = self.retries.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+
+            // A dropped job's report goes unanswered, which reads as not delivered
+            let Some(jobs) = retries.upgrade() else {
+                return;
+            };
+            if let Err(err) = jobs.try_send(job) {
+                error!(
+                    "Dropped push notification retry, queue is full or closed: {}",
+                    err
+                );
+            }
+        });
+    }
+
+    async fn remove(&self, subscription: &Subscription) {
+        if let Err(err) = sqlx::query("DELETE FROM \"PushSubscriptions\" WHERE endpoint = $1")
+            .bind(&subscription.endpoint)
+            .execute(&self.db.pool)
+            .await
+        {
+            error!("Failed to remove push subscription: {}", err);
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -65,12 +555,11 @@ pub struct VapidKey {
 }
 
 #[axum::debug_handler]
-pub async fn vapid() -> axum::Json<VapidKey> {
+pub async fn vapid(State(state): State<AppState>) -> axum::Json<VapidKey> {
     info!("Getting VAPID key");
-    let public_key: String = std::env::var("VAPID_PUBLIC").expect("VAPID key not set");
 
     let key = VapidKey {
-        public_key: public_key,
+        public_key: state.push.public_key().to_string(),
     };
 
     Json(key)
@@ -89,41 +578,321 @@ pub struct Keys {
     p256dh: String,
 }
 
+/// Adds a device for the user, subscribing is also taken as turning notifications on
 pub async fn register(
     State(state): State<AppState>,
     headers: HeaderMap,
     Json(webpush): Json<WebPush>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let access_token = match headers.get("x-access-token") {
-        Some(token) => token
-            .to_str()
-            .expect("access_token was an invalid string")
-            .to_string(),
-        None => {
-            error!("User attempted to register without access_token");
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                "Unauthorzed, no access_token provided".to_string(),
-            ));
+    let user = get_user_from_headers(&state.db, &headers).await?;
+
+    let mut tx = state.db.pool.begin().await.map_err(ApiError::sqlx)?;
+
+    // Browsers reuse an endpoint when another user logs in on the same device
+    sqlx::query("INSERT INTO \"PushSubscriptions\" (user_id, endpoint, p256dh, auth, vapid_key) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (endpoint) DO UPDATE SET user_id = $1, p256dh = $3, auth = $4, vapid_key = $5, needs_reregister = FALSE")
+        .bind(&user.id)
+        .bind(webpush.endpoint)
+        .bind(webpush.keys.p256dh)
+        .bind(webpush.keys.auth)
+        .bind(state.push.public_key())
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+    sqlx::query("UPDATE \"Users\" SET is_notify = TRUE WHERE id = $1")
+        .bind(&user.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::sqlx)?;
+
+    tx.commit().await.map_err(ApiError::sqlx)?;
+
+    info!("Registered push subscription for {}", user.name);
+    Ok(())
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct DeviceSubscription {
+    pub endpoint: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Made with an old VAPID key, the browser has to subscribe again to get notifications
+    pub needs_reregister: bool,
+}
+
+pub async fn get_subscriptions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DeviceSubscription>>, (StatusCode, String)> {
+    let user = get_user_from_headers(&state.db, &headers).await?;
+
+    match sqlx::query_as::<_, DeviceSubscription>("SELECT endpoint, created_at, needs_reregister FROM \"PushSubscriptions\" WHERE user_id = $1 ORDER BY created_at")
+        .bind(&user.id)
+        .fetch_all(&state.db.pool)
+        .await
+    {
+        Ok(subscriptions) => Ok(Json(subscriptions)),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Unregister {
+    pub endpoint: String,
+}
+
+pub async fn unregister(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(unregister): Json<Unregister>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let user = get_user_from_headers(&state.db, &headers).await?;
+
+    match sqlx::query("DELETE FROM \"PushSubscriptions\" WHERE user_id = $1 AND endpoint = $2")
+        .bind(&user.id)
+        .bind(unregister.endpoint)
+        .execute(&state.db.pool)
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => Err((
+            StatusCode::NOT_FOUND,
+            "No such push subscription".to_string(),
+        )),
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotificationPreferences {
+    pub enabled: bool,
+    pub assignment: bool,
+    pub shift_reminder: bool,
+    pub broadcast: bool,
+}
+
+impl From<&User> for NotificationPreferences {
+    fn from(user: &User) -> Self {
+        NotificationPreferences {
+            enabled: user.is_notify,
+            assignment: user.notify_assignment,
+            shift_reminder: user.notify_shift,
+            broadcast: user.notify_broadcast,
         }
-    };
+    }
+}
 
-    let user = get_user_helper(&state.db, access_token.clone()).await?;
-    if let Err(e) = sqlx::query!(
-        "UPDATE \"Users\" SET endpoint = $2, p256dh = $3, auth = $4 WHERE id = $1",
-        user.id,
-        webpush.endpoint,
-        webpush.keys.p256dh,
-        webpush.keys.auth
-    )
-    .execute(&state.db.pool)
-    .await
+pub async fn get_preferences(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<NotificationPreferences>, (StatusCode, String)> {
+    let user = get_user_from_headers(&state.db, &headers).await?;
+    Ok(Json(NotificationPreferences::from(&user)))
+}
+
+pub async fn set_preferences(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(preferences): Json<NotificationPreferences>,
+) -> Result<Json<NotificationPreferences>, (StatusCode, String)> {
+    let user = get_user_from_headers(&state.db, &headers).await?;
+
+    match sqlx::query_as::<_, User>("UPDATE \"Users\" SET is_notify = $2, notify_assignment = $3, notify_shift = $4, notify_broadcast = $5 WHERE id = $1 RETURNING *")
+        .bind(&user.id)
+        .bind(preferences.enabled)
+        .bind(preferences.assignment)
+        .bind(preferences.shift_reminder)
+        .bind(preferences.broadcast)
+        .fetch_one(&state.db.pool)
+        .await
     {
-        error!("Error trying to update WebPush details: {e}");
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error trying to update WebPush details: {e}"),
-        ));
-    };
-    Ok(())
+        Ok(user) => Ok(Json(NotificationPreferences::from(&user))),
+        Err(err) => Err(ApiError::sqlx(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{server::conn::http1, service::service_fn};
+    use hyper_util::rt::TokioIo;
+    use std::{collections::VecDeque, convert::Infallible, sync::Mutex, time::Instant};
+    use tokio::net::TcpListener;
+
+    type Requests = Arc<Mutex<Vec<HeaderMap>>>;
+
+    /// A push service answering with `responses` (status, Retry-After) in order and 201 after,
+    /// recording the headers of every request it gets
+    async fn mock_push_service(
+        responses: Vec<(StatusCode, Option<&'static str>)>,
+    ) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/push/ada", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let responses = Arc::new(Mutex::new(VecDeque::from(responses)));
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (requests, responses) = (recorded.clone(), responses.clone());
+                let service = service_fn(move |request: Request<hyper::body::Incoming>| {
+                    requests.lock().unwrap().push(request.headers().clone());
+                    let (status, retry_after) = responses
+                        .lock()
+                        .unwrap()
+                        .pop_front()
+                        .unwrap_or((StatusCode::CREATED, None));
+
+                    let mut response = http::Response::builder().status(status);
+                    if let Some(retry_after) = retry_after {
+                        response = response.header(RETRY_AFTER, retry_after);
+                    }
+                    async move { Ok::<_, Infallible>(response.body(Body::empty()).unwrap()) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (url, requests)
+    }
+
+    /// A scout subscribed from one browser whose push service is the mock
+    async fn setup(
+        responses: Vec<(StatusCode, Option<&'static str>)>,
+    ) -> Option<(Db, PushService, User, Requests)> {
+        let db = Db::for_test().await?;
+        let (endpoint, requests) = mock_push_service(responses).await;
+
+        sqlx::query("INSERT INTO \"Users\" (id, name, is_notify, is_admin, access_token) VALUES ('ada', 'Ada', TRUE, FALSE, 'a')")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO \"PushSubscriptions\" (user_id, endpoint, p256dh, auth) VALUES ('ada', $1, $2, $3)")
+            .bind(endpoint)
+            .bind(public_key_of(&ES256KeyPair::generate()))
+            .bind(Base64UrlUnpadded::encode_string(&[7; 16]))
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let user = sqlx::query_as::<_, User>("SELECT * FROM \"Users\" WHERE id = 'ada'")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+
+        let push = PushService::with_key_pair(
+            db.clone(),
+            ES256KeyPair::generate(),
+            "mailto:admin@example.com".to_string(),
+        );
+        Some((db, push, user, requests))
+    }
+
+    fn assignment() -> PushMessage {
+        PushMessage::Assignment {
+            match_key: "2024casj_qm1".to_string(),
+            team_key: "frc254".to_string(),
+            color: "red".to_string(),
+        }
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers, 3), Duration::from_secs(8));
+        assert_eq!(backoff(30), MAX_BACKOFF);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("5"));
+        assert_eq!(retry_after(&headers, 3), Duration::from_secs(5));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("86400"));
+        assert_eq!(retry_after(&headers, 1), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn delivers_with_ttl_urgency_and_topic() {
+        let Some((db, push, user, requests)) = setup(vec![]).await else {
+            return;
+        };
+
+        assert_eq!(
+            push.deliver_to_user(&user, assignment()).await,
+            PushStatus::Delivered
+        );
+
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["TTL"], "900");
+        assert_eq!(requests[0]["Urgency"], "high");
+        assert_eq!(requests[0]["Topic"], "assignment");
+
+        db.drop_test().await;
+    }
+
+    #[tokio::test]
+    async fn retries_rate_limits_and_server_errors() {
+        let Some((db, push, user, requests)) = setup(vec![
+            (StatusCode::SERVICE_UNAVAILABLE, None),
+            (StatusCode::TOO_MANY_REQUESTS, Some("0")),
+        ])
+        .await
+        else {
+            return;
+        };
+
+        let started = Instant::now();
+        assert_eq!(
+            push.deliver_to_user(&user, assignment()).await,
+            PushStatus::Delivered
+        );
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        // The 503 didn't say how long to wait, so the first backoff was used
+        assert!(started.elapsed() >= backoff(1));
+
+        db.drop_test().await;
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let Some((db, push, user, requests)) =
+            setup(vec![
+                (StatusCode::INTERNAL_SERVER_ERROR, Some("0"));
+                MAX_ATTEMPTS as usize + 1
+            ])
+            .await
+        else {
+            return;
+        };
+
+        assert_eq!(
+            push.deliver_to_user(&user, assignment()).await,
+            PushStatus::Failed
+        );
+        assert_eq!(requests.lock().unwrap().len(), MAX_ATTEMPTS as usize);
+
+        db.drop_test().await;
+    }
+
+    #[tokio::test]
+    async fn removes_gone_subscriptions() {
+        for status in [StatusCode::NOT_FOUND, StatusCode::GONE] {
+            let Some((db, push, user, requests)) = setup(vec![(status, None)]).await else {
+                return;
+            };
+
+            assert_eq!(
+                push.deliver_to_user(&user, assignment()).await,
+                PushStatus::Failed
+            );
+            assert_eq!(requests.lock().unwrap().len(), 1);
+
+            let subscriptions: i64 =
+                sqlx::query_scalar("SELECT COUNT(*) FROM \"PushSubscriptions\"")
+                    .fetch_one(&db.pool)
+                    .await
+                    .unwrap();
+            assert_eq!(subscriptions, 0, "{status} should remove the subscription");
+
+            db.drop_test().await;
+        }
+    }
 }
@@ -1,20 +1,60 @@
 use crate::{
-    model::{self, AppState},
+    admin::get_user_helper,
+    coverage,
+    events::SseReturn,
+    model::{self, AppState, User},
     submit,
+    webpush::PushMessage,
 };
-use axum::response::sse::Event;
 use bimap::BiMap;
 use serde::{Deserialize, Serialize};
 use socketioxide::{
-    extract::{Data, SocketRef, State},
+    extract::{Data, SocketRef, State, TryData},
+    operators::Operators,
+    socket::DisconnectReason,
     socket::Sid,
+    SocketIo,
 };
+use std::collections::HashMap;
 use tracing::{error, info};
 
+/// Scouts connect here, admins to their own namespace so they are never counted as online scouts
+pub const SCOUT_NS: &str = "/socket.io";
+pub const ADMIN_NS: &str = "/admin";
+
+/// Sockets in one of our namespaces
+fn namespace(io: &SocketIo, path: &'static str) -> Operators {
+    io.of(path)
+        .expect("Socket namespaces are registered at startup")
+}
+
 pub struct QueueManager {
-    robot_queue: Vec<(String, String)>, // team_key, team_color
-    name_to_sid: BiMap<String, Sid>,
-    pub matches: Vec<String>, // match keys matches.len() - 1 is the furthest
+    robot_queue: Vec<(String, String, String)>, // team_key, team_color, match_key
+    /// Connected scouts by `Users.id`, names aren't unique so they are only shown
+    id_to_sid: BiMap<String, Sid>,
+    names: HashMap<String, String>, // scout id: scout name
+    pending: Vec<String>,           // scout ids in the order they queued
+    pub matches: Vec<String>,       // match keys matches.len() - 1 is the furthest
+    pub assignments: Vec<Assignment>,
+}
+
+/// Which scout is watching which robot, kept to remind them when their match starts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Assignment {
+    pub scout_id: String,
+    pub scout_name: String,
+    pub match_key: String,
+    pub team_key: String,
+    pub color: String,
+}
+
+/// Everything the admin dashboard needs to start in sync, events after `last_event_id` are changes to it
+#[derive(Debug, Serialize)]
+pub struct QueueSnapshot {
+    pub online: Vec<String>,
+    pub queued: Vec<String>,
+    pub assigned: Vec<Assignment>,
+    pub last_event_id: u64,
 }
 
 #[derive(Serialize)]
@@ -24,6 +64,12 @@ pub struct AdminRet {
     match_key: String,
 }
 
+/// Sent by scouts as the socket's auth payload
+#[derive(Deserialize)]
+pub struct SocketAuth {
+    access_token: String,
+}
+
 #[derive(Deserialize)]
 pub struct NewMatchAuto {
     red_teams: Vec<String>,
@@ -35,11 +81,13 @@ pub struct NewMatchAuto {
 pub struct NewMatchManual {
     red_teams: Vec<String>,
     blue_teams: Vec<String>,
+    /// Scout ids, as in `Users.id`
     red_scouts: Vec<String>,
     blue_scouts: Vec<String>,
     match_key: String,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize)]
 pub struct TeamMatchToScouts {
     team: String,
@@ -51,90 +99,208 @@ impl QueueManager {
     pub fn new() -> Self {
         QueueManager {
             robot_queue: vec![],
-            name_to_sid: BiMap::new(),
+            id_to_sid: BiMap::new(),
+            names: HashMap::new(),
+            pending: vec![],
             matches: vec![],
+            assignments: vec![],
         }
     }
 
-    pub fn get_next_robot(&mut self) -> Option<(String, String)> {
+    /// Records an assignment and lets the scout know with a push notification
+    fn assign(&mut self, state: &AppState, assignment: Assignment) {
+        state
+            .events
+            .publish(SseReturn::ScoutAssigned(assignment.clone()));
+        state.push.notify_scout(
+            assignment.scout_id.clone(),
+            PushMessage::Assignment {
+                match_key: assignment.match_key.clone(),
+                team_key: assignment.team_key.clone(),
+                color: assignment.color.clone(),
+            },
+        );
+        self.assignments.push(assignment);
+    }
+
+    /// Reminds everyone scouting a match that it's starting
+    fn remind_starting(&self, state: &AppState, match_key: &str) {
+        for assignment in self
+            .assignments
+            .iter()
+            .filter(|assignment| assignment.match_key == match_key)
+        {
+            state.push.notify_scout(
+                assignment.scout_id.clone(),
+                PushMessage::MatchStarting {
+                    match_key: assignment.match_key.clone(),
+                    team_key: assignment.team_key.clone(),
+                    color: assignment.color.clone(),
+                },
+            );
+        }
+
+        info!("Notified scouts that {} is starting", match_key);
+    }
+
+    fn connect(&mut self, state: &AppState, user: User, sid: Sid) {
+        self.id_to_sid.insert(user.id.clone(), sid);
+        self.names.insert(user.id, user.name.clone());
+        state.events.publish(SseReturn::ScoutConnected(user.name));
+    }
+
+    /// Forgets the scout on a socket, returning their id
+    fn disconnect(&mut self, state: &AppState, sid: Sid) -> Option<String> {
+        let (scout_id, _) = self.id_to_sid.remove_by_right(&sid)?;
+
+        // A scout who left can't be sent a robot
+        self.dequeue(state, &scout_id);
+        let name = self
+            .names
+            .remove(&scout_id)
+            .unwrap_or_else(|| scout_id.clone());
+        state.events.publish(SseReturn::ScoutDisconnected(name));
+        Some(scout_id)
+    }
+
+    /// Display name of a connected scout, their id if they already left
+    fn name_of(&self, scout_id: &str) -> String {
+        self.names
+            .get(scout_id)
+            .cloned()
+            .unwrap_or_else(|| scout_id.to_string())
+    }
+
+    /// Id of the scout on a socket
+    fn scout_on(&self, sid: &Sid) -> Option<String> {
+        self.id_to_sid.get_by_right(sid).cloned()
+    }
+
+    fn queue(&mut self, state: &AppState, scout_id: &str) {
+        if !self.pending.iter().any(|id| id == scout_id) {
+            self.pending.push(scout_id.to_string());
+            state
+                .events
+                .publish(SseReturn::QueuedScout(self.name_of(scout_id)));
+        }
+    }
+
+    fn dequeue(&mut self, state: &AppState, scout_id: &str) {
+        if let Some(index) = self.pending.iter().position(|id| id == scout_id) {
+            self.pending.remove(index);
+            state
+                .events
+                .publish(SseReturn::DeQueuedScout(self.name_of(scout_id)));
+        }
+    }
+
+    /// Taken while the queue is locked, so no event changing it can be published in between
+    pub fn snapshot(&self, state: &AppState) -> QueueSnapshot {
+        let mut online: Vec<String> = self.names.values().cloned().collect();
+        online.sort();
+
+        QueueSnapshot {
+            online,
+            queued: self.pending.iter().map(|id| self.name_of(id)).collect(),
+            assigned: self.assignments.clone(),
+            last_event_id: state.events.last_id(),
+        }
+    }
+
+    /// Robots in a match still waiting for a scout, as (team_key, team_color)
+    pub fn unassigned_robots(&self, match_key: &str) -> Vec<(String, String)> {
+        self.robot_queue
+            .iter()
+            .filter(|robot| robot.2 == match_key)
+            .map(|(team_key, color, _)| (team_key.clone(), color.clone()))
+            .collect()
+    }
+
+    /// Socket of a connected scout, by `Users.id`
+    pub fn sid_of(&self, scout_id: &str) -> Option<Sid> {
+        self.id_to_sid.get_by_left(scout_id).copied()
+    }
+
+    pub fn get_next_robot(&mut self) -> Option<(String, String, String)> {
         self.robot_queue.pop()
     }
 
     pub async fn assign_robots(
         &mut self,
-        admin_socket: &SocketRef,
+        io: &SocketIo,
         robots: Vec<String>,
         scouts: &mut Vec<Sid>,
         state: &AppState,
         color: String,
     ) {
-        let upstream = state.sse_upstream.lock().await;
-        for team in robots.iter() {
-            if robots.is_empty() {
-                let mut robots_colors = robots
-                    .into_iter()
-                    .map(|robot| (robot, color.clone()))
-                    .collect::<Vec<(String, String)>>();
-                self.robot_queue.append(&mut robots_colors);
+        let Some(match_key) = self.matches.last().cloned() else {
+            error!("Assigning robots before any match was queued");
+            return;
+        };
+
+        let mut robots = robots.into_iter();
+        while let Some(team) = robots.next() {
+            let Some(scout) = scouts.pop() else {
+                // The rest wait for the next scouts to queue
+                self.robot_queue.extend(
+                    std::iter::once(team)
+                        .chain(robots)
+                        .map(|robot| (robot, color.clone(), match_key.clone())),
+                );
                 info!("Out of scouts");
                 return;
-            }
-            let scout = scouts.pop().expect("Scout queue is empty");
-            match admin_socket.to(scout).emit("team_to_scout", team) {
+            };
+            match namespace(io, SCOUT_NS)
+                .to(scout)
+                .emit("team_to_scout", &team)
+            {
                 Ok(_) => info!("Team sent to scout"),
                 Err(e) => error!("Error sending team to scout: {}", e),
             }
 
-            let data = submit::SseReturn::DeQueuedScout(
-                self.name_to_sid
-                    .get_by_right(&scout)
-                    .unwrap_or(&"Fake name".to_string())
-                    .clone(),
-            );
+            let Some(scout_id) = self.scout_on(&scout) else {
+                error!("Assigned {} to a socket with no scout", team);
+                continue;
+            };
 
-            match upstream.send(Ok(Event::default().data(
-                serde_json::to_string(&data).expect("SseReturn struct not serializable"),
-            ))) {
-                Ok(_) => info!("Dequeued user"),
-                Err(err) => error!("Failed to dequeue user: {}", err),
-            }
+            self.dequeue(state, &scout_id);
+
+            self.assign(
+                state,
+                Assignment {
+                    scout_name: self.name_of(&scout_id),
+                    scout_id,
+                    match_key: match_key.clone(),
+                    team_key: team,
+                    color: color.clone(),
+                },
+            );
         }
     }
 
     /// For manual matches, scouts should be the scout list, for auto matches, the scout should be pending_scouts
     pub async fn create_match(
         &mut self,
-        admin_socket: &SocketRef,
+        io: &SocketIo,
         red_robots: Vec<String>,
         blue_robots: Vec<String>,
         red_scouts: &mut Vec<Sid>,
         blue_scouts: &mut Vec<Sid>,
         state: &model::AppState,
     ) {
-        self.assign_robots(
-            admin_socket,
-            red_robots,
-            red_scouts,
-            state,
-            "red".to_string(),
-        )
-        .await;
-        self.assign_robots(
-            admin_socket,
-            blue_robots,
-            blue_scouts,
-            state,
-            "blue".to_string(),
-        )
-        .await;
+        self.assign_robots(io, red_robots, red_scouts, state, "red".to_string())
+            .await;
+        self.assign_robots(io, blue_robots, blue_scouts, state, "blue".to_string())
+            .await;
     }
 }
-pub async fn queue_scout_handler(
-    socket: SocketRef,
-    scout_name: Data<String>,
-    state: State<model::AppState>,
-) {
+pub async fn queue_scout_handler(socket: SocketRef, state: State<model::AppState>, io: SocketIo) {
     let mut manager = state.queue_manager.lock().await;
+    let Some(scout_id) = manager.scout_on(&socket.id) else {
+        error!("Socket {} queued without logging in", socket.id);
+        return;
+    };
+    let scout_name = manager.name_of(&scout_id);
     let socket_rooms = socket
         .rooms()
         .unwrap()
@@ -153,27 +319,29 @@ pub async fn queue_scout_handler(
         error!("Pending scout requested match");
     }
 
-    info!("Scout name: {}", scout_name.0);
+    info!("Scout name: {}", scout_name);
 
     socket
         .join("pending_scouts")
         .expect("To be able to join a room");
 
-    info!("Added {} to the pending_scouts room", scout_name.0);
+    info!("Added {} to the pending_scouts room", scout_name);
+    manager.queue(&state, &scout_id);
     match manager.get_next_robot() {
-        Some(robot) => {
+        Some((team_key, color, curr_match)) => {
             info!(
                 "Robot avaliable\nRemoved {} from the pending_scouts room",
-                scout_name.0
+                scout_name
             );
 
             socket
                 .leave("pending_scouts")
                 .expect("To be able to leave a room");
+            manager.dequeue(&state, &scout_id);
             socket
                 .join("assigned_scouts")
                 .expect("To be able to join a room");
-            match socket.emit("assign_team", robot.clone()) {
+            match socket.emit("assign_team", (team_key.clone(), color.clone())) {
                 Ok(_) => info!("Assignment Sent Back to Scout"),
                 Err(err) => {
                     error!("Failed to Send Assignment Back to Scout");
@@ -181,27 +349,24 @@ pub async fn queue_scout_handler(
                 }
             };
 
-            let num_of_queued_matches = f64::ceil(
-                socket
-                    .within("pending_scouts")
-                    .sockets()
-                    .unwrap_or(vec![])
-                    .len() as f64
-                    / 6.0,
-            ) as usize;
-
-            let curr_match = manager.matches[manager.matches.len() - num_of_queued_matches].clone();
+            manager.assign(
+                &state,
+                Assignment {
+                    scout_id,
+                    scout_name: scout_name.clone(),
+                    match_key: curr_match.clone(),
+                    team_key: team_key.clone(),
+                    color,
+                },
+            );
 
             let admin_ret = AdminRet {
-                team_key: robot.0,
-                scout_name: scout_name.0,
+                team_key,
+                scout_name,
                 match_key: curr_match,
             };
 
-            match socket
-                .broadcast()
-                .emit("team_match_assigned_admin", admin_ret)
-            {
+            match namespace(&io, ADMIN_NS).emit("team_match_assigned_admin", admin_ret) {
                 Ok(_) => info!("Assignment Sent to Admin"),
                 Err(err) => {
                     error!("Assignment Failed to Send to Admin\nError: {}", err);
@@ -215,11 +380,7 @@ pub async fn queue_scout_handler(
     }
 }
 
-pub async fn dequeue_scout_handler(
-    socket: SocketRef,
-    scout_name: Data<String>,
-    state: State<model::AppState>,
-) {
+pub async fn dequeue_scout_handler(socket: SocketRef, state: State<model::AppState>) {
     let rooms: Vec<String> = socket
         .rooms()
         .unwrap_or(vec![])
@@ -229,8 +390,8 @@ pub async fn dequeue_scout_handler(
 
     if !rooms.contains(&"pending_scouts".to_string()) {
         error!(
-            "Attempted to dequeue scout: {} that was not pending\nScout was: {:?}",
-            scout_name.0, rooms
+            "Attempted to dequeue socket: {} that was not pending\nScout was: {:?}",
+            socket.id, rooms
         );
         return;
     }
@@ -240,27 +401,28 @@ pub async fn dequeue_scout_handler(
         Err(err) => error!("Failed to remove scout from pending_scouts room: {}", err),
     }
 
-    let data = submit::SseReturn::DeQueuedScout(scout_name.0);
-
-    let upstream = state.sse_upstream.lock().await;
-    match upstream.send(Ok(Event::default().data(
-        serde_json::to_string(&data).expect("SseReturn struct not serializable"),
-    ))) {
-        Ok(_) => info!("Dequeued user"),
-        Err(err) => error!("Failed to dequeue user: {}", err),
+    let mut manager = state.queue_manager.lock().await;
+    if let Some(scout_id) = manager.scout_on(&socket.id) {
+        manager.dequeue(&state, &scout_id);
     }
+    info!("Dequeued user");
 }
 
 pub async fn new_match_auto_handler(
-    socket: SocketRef,
     match_info: Data<NewMatchAuto>,
     state: State<model::AppState>,
+    io: SocketIo,
 ) {
     let mut manager = state.queue_manager.lock().await;
 
+    // Queuing the next match means the previous one is being played
+    if let Some(previous) = manager.matches.last() {
+        manager.remind_starting(&state, previous);
+        coverage::check_when_complete(state.0.clone(), previous.clone());
+    }
     manager.matches.push(match_info.0.match_key);
 
-    let queued_scouts: Vec<Vec<Sid>> = socket
+    let queued_scouts: Vec<Vec<Sid>> = namespace(&io, SCOUT_NS)
         .to("pending_scouts")
         .sockets()
         .unwrap_or(vec![])
@@ -273,7 +435,7 @@ pub async fn new_match_auto_handler(
 
     manager
         .create_match(
-            &socket,
+            &io,
             match_info.0.red_teams,
             match_info.0.blue_teams,
             &mut queued_scouts[0].clone(), // this is fine because a mutable reference is just needed to appened scouts to
@@ -284,43 +446,38 @@ pub async fn new_match_auto_handler(
 }
 
 pub async fn new_match_manual_handler(
-    socket: SocketRef,
     match_info: Data<NewMatchManual>,
     state: State<model::AppState>,
+    io: SocketIo,
 ) {
     let mut manager = state.queue_manager.lock().await;
 
+    // Queuing the next match means the previous one is being played
+    if let Some(previous) = manager.matches.last() {
+        manager.remind_starting(&state, previous);
+        coverage::check_when_complete(state.0.clone(), previous.clone());
+    }
     manager.matches.push(match_info.0.match_key);
 
-    let mut red_scouts = match_info
-        .0
-        .red_scouts
-        .into_iter()
-        .map(|scout| {
-            manager
-                .name_to_sid
-                .get_by_left(&scout)
-                .expect("Fatal: Scout SID is unknown")
-                .clone()
-        })
-        .collect::<Vec<Sid>>();
-
-    let mut blue_scouts = match_info
-        .0
-        .blue_scouts
-        .into_iter()
-        .map(|scout| {
-            manager
-                .name_to_sid
-                .get_by_left(&scout)
-                .expect("Fatal: Scout SID is unknown")
-                .clone()
-        })
-        .collect::<Vec<Sid>>();
+    // Scouts who went offline since the admin picked them are left out rather than panicking
+    let sids = |scouts: Vec<String>| {
+        scouts
+            .into_iter()
+            .filter_map(|scout_id| {
+                let sid = manager.sid_of(&scout_id);
+                if sid.is_none() {
+                    error!("Scout {} isn't connected", scout_id);
+                }
+                sid
+            })
+            .collect::<Vec<Sid>>()
+    };
+    let mut red_scouts = sids(match_info.0.red_scouts);
+    let mut blue_scouts = sids(match_info.0.blue_scouts);
 
     manager
         .create_match(
-            &socket,
+            &io,
             match_info.0.red_teams,
             match_info.0.blue_teams,
             &mut red_scouts,
@@ -332,27 +489,143 @@ pub async fn new_match_manual_handler(
 
 pub async fn submit_team_match_handler(
     socket: SocketRef,
-    team_match_data: Data<model::TeamMatch>,
+    Data(mut team_match): Data<model::TeamMatch>,
     state: State<model::AppState>,
 ) {
     match socket.leave("assigned_scouts") {
         Ok(_) => info!("Scout left "),
         Err(err) => error!("Failed to remove scout from assigned scouts: {}", err),
     }
-    submit::submit_team_match(&state, team_match_data.0).await;
+
+    {
+        let mut manager = state.queue_manager.lock().await;
+        let Some(scout_id) = manager.scout_on(&socket.id) else {
+            error!("Socket {} submitted a match without logging in", socket.id);
+            return;
+        };
+
+        // Submitted robots are no longer waiting on a scout
+        manager.assignments.retain(|assignment| {
+            assignment.scout_id != scout_id
+                || assignment.match_key != team_match.match_key
+                || assignment.team_key != team_match.team_key
+        });
+        team_match.scout_id = scout_id;
+    }
+
+    submit::submit_team_match(&state, team_match).await;
 }
 
-pub async fn on_connect(
+pub async fn on_disconnect(
     socket: SocketRef,
-    Data(username): Data<String>,
+    reason: DisconnectReason,
     State(state): State<AppState>,
 ) {
     let mut manager = state.queue_manager.lock().await;
-    manager.name_to_sid.insert(username, socket.id);
+    if let Some(scout_id) = manager.disconnect(state, socket.id) {
+        info!("{} disconnected: {:?}", scout_id, reason);
+    }
+}
+
+/// Registers the scout and admin namespaces, handlers get `io` to reach the other namespace
+pub fn register(io: &SocketIo) {
+    let sockets = io.clone();
+    io.ns(
+        SCOUT_NS,
+        move |socket: SocketRef, auth: TryData<SocketAuth>, state: State<AppState>| {
+            on_connect(socket, auth, state, sockets.clone())
+        },
+    );
+
+    let sockets = io.clone();
+    io.ns(
+        ADMIN_NS,
+        move |socket: SocketRef, auth: TryData<SocketAuth>, state: State<AppState>| {
+            on_admin_connect(socket, auth, state, sockets.clone())
+        },
+    );
+}
 
-    socket.on("queue_scout", queue_scout_handler);
+/// The user a socket's access token belongs to
+async fn authenticate(auth: TryData<SocketAuth>, state: &AppState) -> Result<User, String> {
+    let auth = auth
+        .0
+        .map_err(|err| format!("Invalid socket auth: {err}"))?;
+
+    get_user_helper(&state.db, auth.access_token)
+        .await
+        .map(|user| user.0)
+        .map_err(|(_, err)| err)
+}
+
+/// Scouts connect with their access token, everything after is keyed on their `Users.id`
+async fn on_connect(
+    socket: SocketRef,
+    auth: TryData<SocketAuth>,
+    State(state): State<AppState>,
+    io: SocketIo,
+) {
+    let user = match authenticate(auth, state).await {
+        Ok(user) => user,
+        Err(err) => {
+            error!("Rejected socket {}: {}", socket.id, err);
+            let _ = socket.disconnect();
+            return;
+        }
+    };
+
+    state
+        .queue_manager
+        .lock()
+        .await
+        .connect(state, user, socket.id);
+
+    socket.on(
+        "queue_scout",
+        move |socket: SocketRef, state: State<AppState>| {
+            queue_scout_handler(socket, state, io.clone())
+        },
+    );
     socket.on("dequeue_sout", dequeue_scout_handler);
-    socket.on("new_match_auto", new_match_auto_handler);
-    socket.on("new_match_manual", new_match_manual_handler);
     socket.on("submit_team_match", submit_team_match_handler);
+    socket.on_disconnect(on_disconnect);
+}
+
+/// The admin dashboard creates matches, it gets the scouts' assignments but is never queued
+async fn on_admin_connect(
+    socket: SocketRef,
+    auth: TryData<SocketAuth>,
+    State(state): State<AppState>,
+    io: SocketIo,
+) {
+    match authenticate(auth, state).await {
+        Ok(user) if user.is_admin => {}
+        Ok(user) => {
+            error!(
+                "Rejected admin socket for {}, who isn't an admin",
+                user.name
+            );
+            let _ = socket.disconnect();
+            return;
+        }
+        Err(err) => {
+            error!("Rejected admin socket {}: {}", socket.id, err);
+            let _ = socket.disconnect();
+            return;
+        }
+    }
+
+    let sockets = io.clone();
+    socket.on(
+        "new_match_auto",
+        move |match_info: Data<NewMatchAuto>, state: State<AppState>| {
+            new_match_auto_handler(match_info, state, sockets.clone())
+        },
+    );
+    socket.on(
+        "new_match_manual",
+        move |match_info: Data<NewMatchManual>, state: State<AppState>| {
+            new_match_manual_handler(match_info, state, io.clone())
+        },
+    );
 }
@@ -14,11 +14,18 @@ export async function subscribeToPush(access_token: string | undefined) {
     applicationServerKey: base64UrlToUint8Array(VAPID_PUBLIC_KEY),
   };
   try {
+    // Subscriptions are tied to the server's key, after it's rotated the old one has to go
+    let existing = await pushManager?.getSubscription();
+    if (existing && localStorage.getItem("vapid_public_key") !== VAPID_PUBLIC_KEY) {
+      await existing.unsubscribe();
+    }
+
     let subscription = await pushManager?.subscribe(subscriptionOptions);
 
     console.log(subscription)
 
     localStorage.setItem("subscription", JSON.stringify(subscription));
+    localStorage.setItem("vapid_public_key", VAPID_PUBLIC_KEY);
 
     await fetch(`${BACKEND_URL}/register`, {
       method: "POST",
@@ -34,6 +41,29 @@ export async function subscribeToPush(access_token: string | undefined) {
   }
 }
 
+export async function unsubscribeFromPush(access_token: string | undefined) {
+  if (!access_token) return;
+
+  let swRegistration = await navigator.serviceWorker.getRegistration();
+  let subscription = await swRegistration?.pushManager.getSubscription();
+  if (!subscription) return;
+
+  try {
+    await fetch(`${BACKEND_URL}/unregister`, {
+      method: "POST",
+      headers: {
+        "Content-Type": "application/json",
+        "x-access-token": access_token,
+      },
+      body: JSON.stringify({ endpoint: subscription.endpoint }),
+    });
+    await subscription.unsubscribe();
+    localStorage.removeItem("subscription");
+  } catch (error) {
+    console.log(error);
+  }
+}
+
 async function fetchVapidKeys() {
   return fetch(`${BACKEND_URL}/vapid`).then((resp) => resp.json().then(data => data.public_key));
 }
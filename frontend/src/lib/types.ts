@@ -5,6 +5,18 @@ export type Scout = {
     status: MatchStatus
 }
 
+export type ScoutStats = {
+    id: string,
+    name: string,
+    matches_scouted: number,
+    pits_scouted: number,
+    photos_uploaded: number,
+    share: number,
+    current_streak: number,
+    longest_streak: number,
+    last_active: string | null
+}
+
 export type MatchStatus = "complete" | "pending" | "not_started"
 
 export type TeamKey = `${number}`
@@ -30,8 +42,8 @@ export type TeamMatchData = {
     tele_speaker_missed: number
     tele_amp_succeed: number
     tele_amp_missed: number
-    trap_succeed: number
-    trap_missed: number
+    trap_succeed: boolean
+    trap_missed: boolean
     stage: string
     skill: number
     is_broke: boolean
@@ -56,9 +68,9 @@ export const default_match_data: TeamMatchData = {
     tele_speaker_missed: 0, //tele done
     tele_amp_succeed: 0, //tele done
     tele_amp_missed: 0, //tele done
-    trap_succeed: 0, //tele done
-    trap_missed: 0, //tele done
-    stage: "onstage", //did it
+    trap_succeed: false, //tele done
+    trap_missed: false, //tele done
+    stage: "Onstage", //did it
     skill: 0, //did it
     is_broke: true,
     is_died: true,